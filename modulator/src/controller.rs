@@ -0,0 +1,190 @@
+use crate::modulation::{SysExComposer, Updater};
+use crate::midi::MidiMessage;
+use crate::spec::updater_from;
+use json::JsonValue;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Controller {
+    ControlChange(u8),
+    Rpn(u16),
+    Nrpn(u16)
+}
+
+impl Controller {
+    fn from_json(j: &JsonValue) -> Result<Controller, String> {
+        match (j["cc"].as_u8(), j["rpn"].as_u16(), j["nrpn"].as_u16()) {
+            (Some(c), None, None) if c < 120 => Ok(Controller::ControlChange(c)), // 120 and up are channel mode messages
+            (None, Some(n), None) if n < 0x4000 => Ok(Controller::Rpn(n)),
+            (None, None, Some(n)) if n < 0x4000 => Ok(Controller::Nrpn(n)),
+            _ => Err(String::from("needs one of cc (0-119), rpn or nrpn (0-16383)"))
+        }
+    }
+
+    // a wide value is sent as 14 bits, MSB then LSB
+    pub fn messages(&self, value: u16, wide: bool, channel: u8) -> Vec<MidiMessage> {
+        let cc = |c: u8, v: u16| MidiMessage::control_change(c, (v & 0x7F) as u8, channel);
+        let (msb, lsb) = if wide { (value >> 7, value) } else { (value, 0) };
+        match self {
+            Controller::ControlChange(c) if wide => vec![cc(*c, msb), cc(*c + 32, lsb)],
+            Controller::ControlChange(c) => vec![cc(*c, value)],
+            Controller::Rpn(n) | Controller::Nrpn(n) => {
+                let (select_msb, select_lsb) = if let Controller::Rpn(_) = self { (101, 100) } else { (99, 98) };
+                let mut m = vec![cc(select_msb, n >> 7), cc(select_lsb, *n), cc(6, msb)];
+                if wide {
+                    m.push(cc(38, lsb));
+                }
+                m.extend([cc(101, 127), cc(100, 127)]); // deselect, so a stray data entry can't change it
+                m
+            }
+        }
+    }
+}
+
+
+// a module driven by controllers rather than bulk sys ex, loaded from a file such as
+// {"name": "JV", "port": "4i4o MIDI 2", "channel": 1, "intervalMs": 100, "controllers": [{"type": "sweep", "name": "cutoff", "min": 0, "max": 127, "cc": 74}]}
+pub struct ControllerDevice {
+    pub name: String,
+    pub port: String,
+    pub channel: u8,
+    pub interval_ms: u64,
+    json: JsonValue
+}
+
+impl ControllerDevice {
+    pub fn load(path: &str) -> Result<ControllerDevice, String> {
+        let contents = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let json = json::parse(&contents).map_err(|e| format!("{}: {}", path, e))?;
+        ControllerDevice::from_json(json).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn from_json(json: JsonValue) -> Result<ControllerDevice, String> {
+        let device = ControllerDevice {
+            name: json["name"].as_str().ok_or("device without a name")?.to_string(),
+            port: json["port"].as_str().ok_or("device without a port")?.to_string(),
+            channel: json["channel"].as_u8().filter(|c| (1..17).contains(c)).ok_or("channel must be 1-16")? - 1,
+            interval_ms: json["intervalMs"].as_u64().unwrap_or(100),
+            json
+        };
+        device.targets()?;
+        Ok(device)
+    }
+
+    // paired and selecting updaters only make sense in the blocks of a sys ex dump
+    pub fn targets(&self) -> Result<Vec<(Updater<'_>, Controller)>, String> {
+        self.json["controllers"].members().map(|j| {
+            let updater = updater_from(j)?;
+            let controller = Controller::from_json(j).map_err(|e| format!("{}: {}", updater.name(), e))?;
+            match (updater, controller) {
+                (Updater::PairedInverseConst(n, _), _) | (Updater::PairedInverseSweep(n), _) | (Updater::SelectOnZero(n), _) =>
+                    Err(format!("{}: not available for controllers", n)),
+                (Updater::WideSweep(n, min, _), Controller::ControlChange(c)) if c >= 32 || min < 0 =>
+                    Err(format!("{}: a wide sweep needs a cc below 32 and min of at least 0", n)),
+                (Updater::Const(n, v), _) if v < 0 =>
+                    Err(format!("{}: {} is not a controller value of 0 to 127", n, v)),
+                (Updater::Sweep(n, min, max), _) | (Updater::ShapedSweep(n, min, max, _), _) if min < 0 || max > 0x7F =>
+                    Err(format!("{}: a sweep of {} to {} is outside the controller values of 0 to 127", n, min, max)),
+                _ => Ok((updater, controller))
+            }
+        }).collect()
+    }
+}
+
+
+// sends each value as it is composed, and only when it has changed since the last update
+pub struct ControllerComposer {
+    channel: u8,
+    controllers: Vec<Controller>,
+    sent: Vec<Option<u16>>,
+    pos: usize,
+    pub messages: Vec<MidiMessage>
+}
+
+impl ControllerComposer {
+    pub fn new(channel: u8, controllers: Vec<Controller>) -> ControllerComposer {
+        ControllerComposer {
+            channel,
+            sent: vec![None; controllers.len()],
+            controllers,
+            pos: 0,
+            messages: Vec::new()
+        }
+    }
+
+    pub fn start(&mut self) {
+        self.pos = 0;
+        self.messages.clear();
+    }
+
+    fn value(&mut self, v: u16, wide: bool) {
+        if self.sent[self.pos] != Some(v) {
+            self.messages.extend(self.controllers[self.pos].messages(v, wide, self.channel));
+            self.sent[self.pos] = Some(v);
+        }
+        self.pos += 1;
+    }
+}
+
+impl SysExComposer for ControllerComposer {
+    fn data(&mut self, d: i8) {
+        self.value(d.max(0) as u16, false);
+    }
+
+    fn data_double_byte(&mut self, d: i16) {
+        self.value(d.clamp(0, 0x3FFF) as u16, true);
+    }
+
+    fn name(&mut self, _: &str) {}
+}
+
+
+#[cfg(test)]
+mod test_controllers {
+    use super::{Controller, ControllerDevice, ControllerComposer};
+    use crate::modulation::SysExComposer;
+
+    fn device(controllers: &str) -> Result<ControllerDevice, String> {
+        let j = json::parse(&format!(r#"{{"name": "gs", "port": "MIDI 2", "channel": 3, "controllers": {}}}"#, controllers)).unwrap();
+        ControllerDevice::from_json(j)
+    }
+
+    #[test]
+    fn builds_cc_rpn_and_nrpn_messages() {
+        let bytes = |c: Controller, v: u16, wide: bool| c.messages(v, wide, 2).iter().flat_map(|m| m.as_bytes()).collect::<Vec<u8>>();
+
+        assert_eq!(bytes(Controller::ControlChange(74), 100, false), [0xB2, 74, 100]);
+        assert_eq!(bytes(Controller::ControlChange(1), 0x3FFF, true), [0xB2, 1, 0x7F, 0xB2, 33, 0x7F]);
+        assert_eq!(bytes(Controller::Rpn(0), 12, false), [0xB2, 101, 0, 0xB2, 100, 0, 0xB2, 6, 12, 0xB2, 101, 127, 0xB2, 100, 127]);
+        assert_eq!(bytes(Controller::Nrpn(0x0120), 0x81, true)[..12], [0xB2, 99, 2, 0xB2, 98, 0x20, 0xB2, 6, 1, 0xB2, 38, 1]);
+    }
+
+    #[test]
+    fn loads_targets_and_rejects_paired_updaters() {
+        let gs = device(r#"[{"type": "sweep", "name": "cutoff", "min": 0, "max": 127, "cc": 74}, {"type": "wideSweep", "name": "tune", "min": 0, "max": 16383, "rpn": 1}]"#).unwrap();
+
+        assert_eq!(gs.channel, 2);
+        assert_eq!(gs.targets().unwrap().iter().map(|(_, c)| *c).collect::<Vec<Controller>>(), [Controller::ControlChange(74), Controller::Rpn(1)]);
+        assert!(device(r#"[{"type": "pairedInverseSweep", "name": "vol", "cc": 7}]"#).is_err());
+        assert!(device(r#"[{"type": "sweep", "name": "a", "min": 0, "max": 1, "cc": 7, "nrpn": 1}]"#).is_err());
+        assert!(device(r#"[{"type": "wideSweep", "name": "a", "min": 0, "max": 1, "cc": 74}]"#).is_err());
+        assert!(device(r#"[{"type": "sweep", "name": "a", "min": -10, "max": 10, "cc": 74}]"#).is_err());
+        assert!(device(r#"[{"type": "sweep", "name": "a", "min": 0, "max": 200, "cc": 74}]"#).is_err());
+    }
+
+    #[test]
+    fn sends_only_values_that_changed() {
+        let mut composer = ControllerComposer::new(0, vec![Controller::ControlChange(7), Controller::ControlChange(10)]);
+        composer.data(100);
+        composer.data(-56);
+        assert_eq!(composer.messages.len(), 2);
+        assert_eq!(composer.messages[1].as_bytes(), [0xB0, 10, 0]);
+
+        composer.start();
+        composer.data(100);
+        composer.data(64);
+
+        assert_eq!(composer.messages.len(), 1);
+        assert_eq!(composer.messages[0].as_bytes(), [0xB0, 10, 64]);
+    }
+}
@@ -1,13 +1,56 @@
 
 use crate::utils::today;
+use json::{JsonValue, object};
+use std::collections::BTreeMap;
+use crate::spec::ParamLimits;
+use rand::{Rng, rngs::StdRng, seq::SliceRandom};
 
 use crate::modulation::{
     SysExComposer,
+    Selector,
     Updater
 };
 
 
+const SYS_EX_START: u8 = 0xF0;
+const SYS_EX_END: u8 = 0xF7;
+const ROLAND_ID: u8 = 0x41;
+const DEVICE_ID: u8 = 0x10;
+const MODEL_ID: u8 = 0x16;
+const RQ1: u8 = 0x11;
+const DT1: u8 = 0x12;
+
+const SYSTEM_AREA: u32 = 0x100000;
+const TIMBRE_AREA: u32 = 0x030000;
+const RHYTHM_SETUP_AREA: u32 = 0x030110;
+const TONE_AREA: u32 = 0x040000;
+const DISPLAY_AREA: u32 = 0x200000;
+const TIMBRE_SIZE: u32 = 0x10;
+pub const TONE_HEADER_SIZE: u32 = 14;
+pub const PARTIAL_SIZE: u32 = 58;
+pub const TONE_SIZE: u32 = TONE_HEADER_SIZE + 4 * PARTIAL_SIZE;
+
+pub const PARTIAL_PREFIXES: [&str; 4] = ["A_1", "B_3", "C_2", "D_4"];
+pub const FIRST_RHYTHM_KEY: u8 = 24;
+pub const RHYTHM_KEYS: u8 = 64;
+
+
+// addresses and sizes are sent as three 7 bit bytes
+pub fn linear_address(address: u32) -> u32 {
+    ((address >> 16) & 0x7F) << 14 | ((address >> 8) & 0x7F) << 7 | (address & 0x7F)
+}
+
+pub fn seven_bit_address(linear: u32) -> u32 {
+    ((linear >> 14) & 0x7F) << 16 | ((linear >> 7) & 0x7F) << 8 | (linear & 0x7F)
+}
+
+fn address_bytes(a: u32) -> Vec<u8> {
+    vec![(a >> 16) as u8, ((a >> 8) & 0xFF) as u8, (a & 0xFF) as u8]
+}
+
+
 pub struct D110SysEx {
+    command: u8,
     sum: u32,
     data: Vec<u8>
 }
@@ -16,11 +59,27 @@ pub struct D110SysEx {
 impl D110SysEx {
     fn new() -> D110SysEx {
         D110SysEx {
+            command: DT1,
             sum: 0,
             data: Vec::<u8>::new()
         }
     }
 
+    pub fn write(address: u32, data: &[u8]) -> D110SysEx {
+        let mut sys_ex = D110SysEx::new();
+        sys_ex.data_vec_u8(address_bytes(address));
+        sys_ex.data_vec_u8(data.to_vec());
+        sys_ex
+    }
+
+    pub fn request(address: u32, size: u32) -> D110SysEx {
+        let mut sys_ex = D110SysEx::new();
+        sys_ex.command = RQ1;
+        sys_ex.data_vec_u8(address_bytes(address));
+        sys_ex.data_vec_u8(address_bytes(seven_bit_address(size)));
+        sys_ex
+    }
+
     fn data_u8(&mut self, v: u8) {
         self.data.push(v);
         self.sum += v as u32;
@@ -39,22 +98,28 @@ impl D110SysEx {
     }
 
     fn checksum(&self) -> u32 {
-        128 - (self.sum % 128)
+        (128 - (self.sum % 128)) % 128
     }
 
-    pub fn to_send(&self) -> Vec<u8> {
-        const SYS_EX_START: u8 = 0xF0;
-        const SYS_EX_END: u8 = 0xF7;
+    // the structure of each pair of partials, which follows the name in set_up_tone
+    pub fn set_structures(&mut self, structures: [u8; 2]) {
+        for (i, s) in structures.iter().enumerate() {
+            let at = 3 + TONE_NAME_LENGTH as usize + i;
+            self.sum = self.sum - self.data[at] as u32 + *s as u32;
+            self.data[at] = *s;
+        }
+    }
 
-        const HEADER: [u8; 5] = [
+    pub fn to_send(&self) -> Vec<u8> {
+        let header: [u8; 5] = [
             SYS_EX_START,
-            0x41, // ID of Roland
-            0x10, // device
-            0x16, // model
-            0x12 // command
+            ROLAND_ID,
+            DEVICE_ID,
+            MODEL_ID,
+            self.command
         ];
         let mut msg = Vec::<u8>::new();
-        for c in HEADER.iter() {
+        for c in header.iter() {
             msg.push(*c);
         }
         for d in self.data.iter() {
@@ -72,60 +137,207 @@ impl SysExComposer for D110SysEx {
     fn data(&mut self, d: i8) {
         self.data_u8(d as u8);
     }
-    fn data_double_byte(&mut self, _: i16) {}
+    fn data_double_byte(&mut self, d: i16) {
+        self.data_u8(((d >> 7) & 0x7F) as u8);
+        self.data_u8((d & 0x7F) as u8);
+    }
     fn name(&mut self, _: &str) {}
 }
 
 
-pub fn init_d110() -> D110SysEx {
-    const RES_ALLOWANCE_FOR_PARTIALS: [u8; 9] = [4, 4, 4, 4, 4, 4, 0, 0, 0];
-    const MIDI_CHANNELS: [u8; 9] = [1, 2, 3, 4, 5, 6, 9, 9, 10];
+// the MT-32 and CM-32L share the D-110's memory map and partial layout, differing in what
+// each part's block holds and in the system settings around it
+pub struct DeviceProfile {
+    pub name: &'static str,
+    output_assign: bool, // individual outputs; the MT-32 has the reverb switch in this byte
+    key_range: bool,
+    reverb_off: [u8; 3], // mode, time, level
+    pub system_limits: ParamLimits,
+    pub rhythm_limits: ParamLimits,
+    dated_system_area: bool,
+    display: bool
+}
 
-    let mut sys_ex = D110SysEx::new();
+pub const D110: DeviceProfile = DeviceProfile {
+    name: "D-110",
+    output_assign: true,
+    key_range: true,
+    reverb_off: [9, 1, 0], // reverb type 1-8, 9=off
+    system_limits: ParamLimits {
+        device: "D-110",
+        params: &[
+            ("reverbMode", 1, 9), ("reverbTime", 0, 7), ("reverbLevel", 0, 7),
+            ("partialReserve1", 0, 32), ("partialReserve2", 0, 32), ("partialReserve3", 0, 32), ("partialReserve4", 0, 32), ("partialReserve5", 0, 32),
+            ("partialReserve6", 0, 32), ("partialReserve7", 0, 32), ("partialReserve8", 0, 32), ("partialReserveRhythm", 0, 32)
+        ]
+    },
+    rhythm_limits: ParamLimits {
+        device: "D-110",
+        params: &[("tone", 0, 127), ("level", 0, 100), ("pan", 0, 14), ("output", 0, 7)] // dry mix, reverb mix, multi 1-6
+    },
+    dated_system_area: true,
+    display: false
+};
+
+pub const MT32: DeviceProfile = DeviceProfile {
+    name: "MT-32",
+    output_assign: false,
+    key_range: false,
+    reverb_off: [0, 1, 0], // mode 0-3 has no off, so level 0
+    system_limits: ParamLimits {
+        device: "MT-32",
+        params: &[
+            ("reverbMode", 0, 3), ("reverbTime", 0, 7), ("reverbLevel", 0, 7),
+            ("partialReserve1", 0, 32), ("partialReserve2", 0, 32), ("partialReserve3", 0, 32), ("partialReserve4", 0, 32), ("partialReserve5", 0, 32),
+            ("partialReserve6", 0, 32), ("partialReserve7", 0, 32), ("partialReserve8", 0, 32), ("partialReserveRhythm", 0, 32)
+        ]
+    },
+    rhythm_limits: ParamLimits {
+        device: "MT-32",
+        params: &[("tone", 0, 127), ("level", 0, 100), ("pan", 0, 14), ("output", 0, 1)] // the reverb switch
+    },
+    dated_system_area: false,
+    display: true
+};
 
-    sys_ex.data_vec_u8(vec![0x10, 0x00, 0x01]); // address to which init data is written
-    sys_ex.data_u8(9); // reverb type 1-8, 9=off
-    sys_ex.data_u8(1); // reverb time 1-8
-    sys_ex.data_u8(0); // reverb level 0-7
+pub const CM32L: DeviceProfile = DeviceProfile {
+    display: false, // no front panel
+    name: "CM-32L",
+    ..MT32
+};
 
-    sys_ex.data_vec_u8(RES_ALLOWANCE_FOR_PARTIALS.to_vec());
-    sys_ex.data_vec_u8(MIDI_CHANNELS.iter().map(|c| c - 1).collect::<Vec<u8>>());
-    sys_ex.data_u8(0);
-    sys_ex.data_str(&today());
+pub const DEVICE_PROFILES: [&DeviceProfile; 3] = [&D110, &MT32, &CM32L];
 
-    sys_ex
+impl DeviceProfile {
+    pub fn named(name: &str) -> Option<&'static DeviceProfile> {
+        DEVICE_PROFILES.iter().find(|p| p.name.replace('-', "").eq_ignore_ascii_case(&name.replace('-', ""))).copied()
+    }
+
+    pub fn init_system(&self) -> D110SysEx {
+        const RES_ALLOWANCE_FOR_PARTIALS: [u8; 9] = [4, 4, 4, 4, 4, 4, 0, 0, 0];
+        const MIDI_CHANNELS: [u8; 9] = [1, 2, 3, 4, 5, 6, 9, 9, 10];
+
+        let mut sys_ex = D110SysEx::new();
+
+        sys_ex.data_vec_u8(vec![0x10, 0x00, 0x01]); // address to which init data is written
+        sys_ex.data_vec_u8(self.reverb_off.to_vec());
+
+        sys_ex.data_vec_u8(RES_ALLOWANCE_FOR_PARTIALS.to_vec());
+        sys_ex.data_vec_u8(MIDI_CHANNELS.iter().map(|c| c - 1).collect::<Vec<u8>>());
+        if self.dated_system_area {
+            sys_ex.data_u8(0);
+            sys_ex.data_str(&today());
+        }
+
+        sys_ex
+    }
+
+    pub fn init_part(&self, number: u8) -> D110SysEx {
+        let mut sys_ex = D110SysEx::new();
+
+        let addr = vec![0x03, 0x00, (0x10 * (number - 1))];
+        // timbre
+        sys_ex.data_vec_u8(addr); // address
+        sys_ex.data_u8(2); // tone group 0-3 (a,b,i,r)
+        sys_ex.data_u8(64 - number); // tone number
+        sys_ex.data_u8(24); // keyShift in semitones, 24 = 0 shift, 27 = +3
+        sys_ex.data_u8(50); // fineTune +/- 50, 50 = 0
+        sys_ex.data_u8(12); // benderRange semitones, 0-24
+        sys_ex.data_u8(2); // note priority monoLast = 0, monoFirst, polyLast, polyFirst
+        sys_ex.data_u8(if self.output_assign && number < 7 { number + 1 } else { 0 }); // outputAssign 0=mix? or MT-32 reverb switch
+        sys_ex.data_u8(0); // dummy/reverb off
+
+        // part
+        sys_ex.data_u8(if number < 7 { 98 } else { 0 });  // outputLevel max 100
+        sys_ex.data_u8(7);  // pan 7 = mid, 0 = R, 15 = L
+        if self.key_range {
+            sys_ex.data_u8(if number < 7 { 0 } else { 0x7F }); // keyRangeLower 0 = C-1
+            sys_ex.data_u8(0x7F); // keyRangeUpper 127 = G9
+        } else {
+            sys_ex.data_vec_u8(vec![0, 0]);
+        }
+        sys_ex.data_u8(0);
+        sys_ex.data_u8(0);
+        sys_ex.data_u8(0);
+        sys_ex.data_u8(0);
+
+        sys_ex
+    }
+
+    // the built in specs, pulled into what this device accepts; only a spec file has to fit as it is
+    pub fn system_spec(&self) -> Vec<Updater<'static>> {
+        let mut spec = SYSTEM_SPEC.to_vec();
+        self.system_limits.clamp(&mut spec);
+        spec
+    }
+
+    pub fn rhythm_spec(&self) -> Vec<Updater<'static>> {
+        let mut spec = RHYTHM_SPEC.to_vec();
+        self.rhythm_limits.clamp(&mut spec);
+        spec
+    }
+
+    // the MT-32's 20 character LCD; ignored by the others so not sent
+    pub fn display_message(&self, text: &str) -> Option<D110SysEx> {
+        if !self.display {
+            return None;
+        }
+        let mut sys_ex = D110SysEx::new();
+        sys_ex.data_vec_u8(address_bytes(DISPLAY_AREA));
+        sys_ex.data_str(&format!("{:<20.20}", text));
+        Some(sys_ex)
+    }
+
+    pub fn setup(&self) -> Vec<D110SysEx> {
+        let mut setup = vec![self.init_system()];
+        setup.extend((1..9).map(|t| self.init_part(t)));
+        setup.extend((1..9).map(set_up_tone));
+        setup.extend(self.display_message(&format!("modulator {}", today())));
+        setup
+    }
+
+    // the keys from FIRST_RHYTHM_KEY on
+    pub fn set_up_rhythm_keys(&self, keys: &[RhythmKey]) -> D110SysEx {
+        let mut sys_ex = set_up_rhythm();
+        for k in keys {
+            sys_ex.data_vec_u8(vec![k.tone, k.level, k.pan, if self.output_assign { k.output } else { k.output.min(1) }]);
+        }
+        sys_ex
+    }
 }
 
-pub fn init_timbre(number: u8) -> D110SysEx {
+
+// the reverb and partial reserves, which follow masterTune, for SYSTEM_SPEC to fill in
+pub fn set_up_system() -> D110SysEx {
     let mut sys_ex = D110SysEx::new();
-    
-    let addr = vec![0x03, 0x00, (0x10 * (number - 1))];
-    // timbre
-    sys_ex.data_vec_u8(addr); // address
-    sys_ex.data_u8(2); // tone group 0-3 (a,b,i,r)
-    sys_ex.data_u8(64 - number); // tone number
-    sys_ex.data_u8(24); // keyShift in semitones, 24 = 0 shift, 27 = +3
-    sys_ex.data_u8(50); // fineTune +/- 50, 50 = 0
-    sys_ex.data_u8(12); // benderRange semitones, 0-24
-    sys_ex.data_u8(2); // note priority monoLast = 0, monoFirst, polyLast, polyFirst
-    sys_ex.data_u8(if number < 7 { number + 1 } else { 0 }); // outputAssign 0=mix?
-    sys_ex.data_u8(0); // dummy/reverb off
-
-    // part
-    sys_ex.data_u8(if number < 7 { 98 } else { 0 });  // outputLevel max 100
-    sys_ex.data_u8(7);  // pan 7 = mid, 0 = R, 15 = L
-    sys_ex.data_u8(if number < 7 { 0 } else { 0x7F }); // keyRangeLower 0 = C-1
-    sys_ex.data_u8(0x7F); // keyRangeUpper 127 = G9
-    sys_ex.data_u8(0);
-    sys_ex.data_u8(0);
-    sys_ex.data_u8(0);
-    sys_ex.data_u8(0);
-    
+    sys_ex.data_vec_u8(address_bytes(SYSTEM_AREA + 1));
     sys_ex
 }
 
+// what a key of the rhythm part plays; tones 0-63 are the tone memory, 64 on the rhythm PCM sounds
+#[derive(Clone, Copy)]
+pub struct RhythmKey {
+    pub tone: u8,
+    pub level: u8, // 0-100
+    pub pan: u8, // 0-14, 7 = mid
+    pub output: u8 // the D-110's output assign, 1 = reverb mix; the MT-32's reverb switch
+}
+
+impl RhythmKey {
+    // each key from C1 plays the next of the rhythm sounds, in the middle and with reverb
+    pub fn defaults() -> Vec<RhythmKey> {
+        (0..RHYTHM_KEYS).map(|k| RhythmKey { tone: 64 + k, level: 80, pan: 7, output: 1 }).collect()
+    }
+}
+
+// the address of the rhythm keys for RHYTHM_SPEC to fill in
+pub fn set_up_rhythm() -> D110SysEx {
+    let mut sys_ex = D110SysEx::new();
+    sys_ex.data_vec_u8(address_bytes(RHYTHM_SETUP_AREA));
+    sys_ex
+}
 
-fn address_of(tone_number: u8) -> u32 {
+pub fn address_of(tone_number: u8) -> u32 {
     if tone_number == 1 {
         0x040000
     } else {
@@ -136,10 +348,7 @@ fn address_of(tone_number: u8) -> u32 {
 pub fn set_up_tone(number: u8) -> D110SysEx {
     let mut sys_ex = D110SysEx::new();
 
-    let a = address_of(number);
-    let a_vec = vec![(a >> 16) as u8, ((a >> 8) & 0xFF) as u8, (a & 0xFF) as u8];
-
-    sys_ex.data_vec_u8(a_vec); // address
+    sys_ex.data_vec_u8(address_bytes(address_of(number))); // address
     //tone name
     sys_ex.data_str(if number < 7 { "tone" } else { "mute" });
     sys_ex.data_u8(number + 0x30);
@@ -152,16 +361,351 @@ pub fn set_up_tone(number: u8) -> D110SysEx {
     sys_ex
 }
 
+
+#[derive(Debug, PartialEq)]
+pub enum D110ReplyError {
+    Framing,
+    NotD110Dt1,
+    #[cfg(test)]
+    NotD110Rq1,
+    Checksum
+}
+
+// the framed body of a D-110 message with the given command, from address to checksum
+fn checked_body(msg: &[u8], command: u8, not_command: D110ReplyError) -> Result<&[u8], D110ReplyError> {
+    if msg.len() < 10 || msg[0] != SYS_EX_START || msg[msg.len() - 1] != SYS_EX_END {
+        return Err(D110ReplyError::Framing);
+    }
+    if msg[1..5] != [ROLAND_ID, DEVICE_ID, MODEL_ID, command] {
+        return Err(not_command);
+    }
+    let body = &msg[5..msg.len() - 1];
+    if body.iter().map(|b| *b as u32).sum::<u32>() % 128 != 0 {
+        return Err(D110ReplyError::Checksum);
+    }
+    Ok(body)
+}
+
+// only the simulator reads requests rather than sending them
+#[cfg(test)]
+pub struct D110Request {
+    pub address: u32,
+    pub size: u32
+}
+
+#[cfg(test)]
+impl D110Request {
+    pub fn parse(msg: &[u8]) -> Result<D110Request, D110ReplyError> {
+        let body = checked_body(msg, RQ1, D110ReplyError::NotD110Rq1)?;
+        if body.len() != 7 {
+            return Err(D110ReplyError::Framing);
+        }
+        let three_bytes = |b: &[u8]| (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        Ok(D110Request {
+            address: three_bytes(&body[..3]),
+            size: linear_address(three_bytes(&body[3..6]))
+        })
+    }
+}
+
+pub struct D110Reply {
+    pub address: u32,
+    pub data: Vec<u8>
+}
+
+const SYSTEM_FIELDS: [&str; 22] = [
+    "masterTune", "reverbMode", "reverbTime", "reverbLevel",
+    "partialReserve1", "partialReserve2", "partialReserve3", "partialReserve4", "partialReserve5",
+    "partialReserve6", "partialReserve7", "partialReserve8", "partialReserveRhythm",
+    "midiChannel1", "midiChannel2", "midiChannel3", "midiChannel4", "midiChannel5",
+    "midiChannel6", "midiChannel7", "midiChannel8", "midiChannelRhythm"
+];
+
+// as written by DeviceProfile::init_part for the D-110
+const TIMBRE_FIELDS: [&str; 12] = [
+    "toneGroup", "toneNumber", "keyShift", "fineTune", "benderRange", "assignMode",
+    "outputAssign", "reverbSwitch", "outputLevel", "pan", "keyRangeLower", "keyRangeUpper"
+];
+
+// as written by set_up_rhythm for each key
+const RHYTHM_KEY_FIELDS: [&str; 4] = ["tone", "level", "pan", "output"];
+
+// as written by set_up_tone, after the 10 character name
+const TONE_HEADER_FIELDS: [&str; 4] = ["structure12", "structure34", "partialEnable", "envelopeMode"];
+const TONE_NAME_LENGTH: u32 = 10;
+
+// each byte of a partial, in the order of PARTIAL_SPEC
+const PARTIAL_FIELDS: [&str; 58] = [
+    "pitchCoarse", "pitchFine", "keyFollowPitch", "allowPitchBend", "waveSource", "pcmSource", "wavePulseWidth",
+    "wavePulseWidthVelocityMod", "pitchEnvelopeDepth", "pitchEnvelopeVelocityMod", "pitchEnvelopeTimeKeyFollow",
+    "env_pitch_attackTime", "env_pitch_decayTime", "env_pitch_slopeTime", "env_pitch_releaseTime",
+    "env_pitch_startLevel", "env_pitch_attackLevel", "env_pitch_breakPoint", "env_pitch_sustainLevel",
+    "env_pitch_releaseLevel", "pitchLfoRate", "pitchLfoDepth", "pitchLfoModSens", "envFilterFreq", "envFilterReso",
+    "envFilterFreqKeyFollow", "envFilterBiasPoint", "envFilterBiasLevel", "envFilterDepth", "envFilterVelocityMod",
+    "envFilterDepthKeyFollow", "envFilterTimeKeyFollow", "env_filter_attackTime", "env_filter_decayTime",
+    "env_filter_attack2Time", "env_filter_slopeTime", "env_filter_releaseTime", "env_filter_attackLevel",
+    "env_filter_breakPoint", "env_filter_attack2Level", "env_filter_sustainLevel", "vol", "amplEnvVelocityMod",
+    "amplEnvLowerBiasPoint", "amplEnvLowerBiasLevel", "amplEnvUpperBiasPoint", "amplEnvUpperBiasLevel",
+    "amplEnvTimeKeyFollow", "amplEnvAttackTimeKeyFollow", "env_amplitude_attackTime", "env_amplitude_decayTime",
+    "env_amplitude_attack2Time", "env_amplitude_slopeTime", "env_amplitude_releaseTime", "env_amplitude_attackLevel",
+    "env_amplitude_breakPoint", "env_amplitude_attack2Level", "env_amplitude_sustainLevel"
+];
+
+// the tone and position within its name of a byte in the tone area
+fn tone_name_at(linear: u32) -> Option<(u32, usize)> {
+    let tones = linear_address(TONE_AREA);
+    if !(tones..tones + 8 * TONE_SIZE).contains(&linear) || (linear - tones) % TONE_SIZE >= TONE_NAME_LENGTH {
+        return None;
+    }
+    Some(((linear - tones) / TONE_SIZE + 1, ((linear - tones) % TONE_SIZE) as usize))
+}
+
+// bytes without a field name are keyed by their address so that nothing is lost
+fn json_key(linear: u32) -> String {
+    field_name(linear).unwrap_or_else(|| format!("{:06X}", seven_bit_address(linear)))
+}
+
+fn field_name(linear: u32) -> Option<String> {
+    let system = linear_address(SYSTEM_AREA);
+    let timbres = linear_address(TIMBRE_AREA);
+    let rhythm = linear_address(RHYTHM_SETUP_AREA);
+    let tones = linear_address(TONE_AREA);
+
+    if (tones..tones + 8 * TONE_SIZE).contains(&linear) {
+        let tone = (linear - tones) / TONE_SIZE + 1;
+        let offset = (linear - tones) % TONE_SIZE;
+        if offset < TONE_NAME_LENGTH {
+            None
+        } else if offset < TONE_HEADER_SIZE {
+            Some(format!("tone{}_{}", tone, TONE_HEADER_FIELDS[(offset - TONE_NAME_LENGTH) as usize]))
+        } else {
+            let partial = ((offset - TONE_HEADER_SIZE) / PARTIAL_SIZE) as usize;
+            let idx = ((offset - TONE_HEADER_SIZE) % PARTIAL_SIZE) as usize;
+            Some(format!("tone{}_partial{}_{}", tone, PARTIAL_PREFIXES[partial], PARTIAL_FIELDS[idx]))
+        }
+    } else if (timbres..timbres + 8 * TIMBRE_SIZE).contains(&linear) {
+        let timbre = (linear - timbres) / TIMBRE_SIZE + 1;
+        TIMBRE_FIELDS.get(((linear - timbres) % TIMBRE_SIZE) as usize).map(|f| format!("timbre{}_{}", timbre, f))
+    } else if (rhythm..rhythm + 4 * RHYTHM_KEYS as u32).contains(&linear) {
+        let key = (linear - rhythm) / 4 + FIRST_RHYTHM_KEY as u32;
+        Some(format!("key{}_{}", key, RHYTHM_KEY_FIELDS[((linear - rhythm) % 4) as usize]))
+    } else if linear >= system {
+        SYSTEM_FIELDS.get((linear - system) as usize).map(|f| f.to_string())
+    } else {
+        None
+    }
+}
+
+impl D110Reply {
+    pub fn parse(msg: &[u8]) -> Result<D110Reply, D110ReplyError> {
+        let body = checked_body(msg, DT1, D110ReplyError::NotD110Dt1)?;
+        Ok(D110Reply {
+            address: (body[0] as u32) << 16 | (body[1] as u32) << 8 | body[2] as u32,
+            data: body[3..body.len() - 1].to_vec()
+        })
+    }
+
+    pub fn values(&self) -> Vec<(String, u8)> {
+        let start = linear_address(self.address);
+        self.data.iter().enumerate()
+            .filter_map(|(i, v)| field_name(start + i as u32).map(|name| (name, *v)))
+            .collect()
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let start = linear_address(self.address);
+        let mut j = object!{
+            address: format!("{:06X}", self.address),
+            size: self.data.len()
+        };
+        for (i, v) in self.data.iter().enumerate() {
+            let linear = start + i as u32;
+            match tone_name_at(linear) {
+                Some((tone, 0)) => j["values"][format!("tone{}_name", tone)] = self.tone_name(tone as u8).into(),
+                Some(_) => {},
+                None => j["values"][json_key(linear)] = (*v).into()
+            }
+        }
+        j
+    }
+
+    pub fn from_json(j: &JsonValue) -> Result<D110Reply, String> {
+        let address = j["address"].as_str().and_then(|a| u32::from_str_radix(a, 16).ok()).ok_or(format!("block without a hex address: {}", j["address"]))?;
+        let size = j["size"].as_u32().ok_or(format!("{:06X}: size missing", address))?;
+        let start = linear_address(address);
+        let data = (start..start + size).map(|linear| match tone_name_at(linear) {
+            Some((tone, idx)) => {
+                let name = j["values"][format!("tone{}_name", tone)].as_str().unwrap_or("");
+                Ok(*name.as_bytes().get(idx).unwrap_or(&b' '))
+            },
+            None => {
+                let key = json_key(linear);
+                j["values"][key.as_str()].as_u8().filter(|v| *v < 0x80).ok_or(format!("{:06X}: {} missing or not a 7 bit value", address, key))
+            }
+        }).collect::<Result<Vec<u8>, String>>()?;
+        Ok(D110Reply { address, data })
+    }
+
+    pub fn tone_name(&self, tone_number: u8) -> Option<String> {
+        let start = (linear_address(address_of(tone_number)) as usize).checked_sub(linear_address(self.address) as usize)?;
+        let name = self.data.get(start..start + TONE_NAME_LENGTH as usize)?;
+        Some(name.iter().map(|c| *c as char).collect::<String>().trim_end().to_string())
+    }
+}
+
+
+// what has been written to the D110's temporary memory, byte by byte, so that a rebuilt block
+// can be sent as just the runs that changed
+pub struct D110Memory {
+    written: BTreeMap<u32, u8>
+}
+
+impl D110Memory {
+    // header, address, checksum and end; a gap no longer than this costs no more to resend than a new message
+    const DT1_OVERHEAD: usize = 10;
+
+    pub fn new() -> D110Memory {
+        D110Memory {
+            written: BTreeMap::new()
+        }
+    }
+
+    fn block(sys_ex: &D110SysEx) -> (u32, Vec<u8>) {
+        let block = D110Reply::parse(&sys_ex.to_send()).unwrap();
+        (linear_address(block.address), block.data)
+    }
+
+    pub fn record(&mut self, sys_ex: &D110SysEx) {
+        let (start, data) = D110Memory::block(sys_ex);
+        for (i, v) in data.iter().enumerate() {
+            self.written.insert(start + i as u32, *v);
+        }
+    }
+
+    pub fn changes(&mut self, sys_ex: &D110SysEx) -> Vec<D110SysEx> {
+        let (start, data) = D110Memory::block(sys_ex);
+        let mut runs: Vec<(usize, usize)> = Vec::new();
+        for (i, v) in data.iter().enumerate() {
+            if self.written.get(&(start + i as u32)) == Some(v) {
+                continue;
+            }
+            match runs.last_mut() {
+                Some((_, last)) if i - *last <= D110Memory::DT1_OVERHEAD + 1 => *last = i,
+                _ => runs.push((i, i))
+            }
+        }
+        self.record(sys_ex);
+        runs.iter().map(|(first, last)| D110SysEx::write(seven_bit_address(start + *first as u32), &data[*first..=*last])).collect()
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PartialWave {
+    Square,
+    Saw,
+    Pcm(u8, u8) // bank (0 for 1, 1 for 2), sample
+}
+
+impl PartialWave {
+    // waveSource then pcmSource, the high byte first as SelectOnZero writes it
+    fn value(&self) -> u16 {
+        match self {
+            PartialWave::Square => 0,
+            PartialWave::Saw => 1 << 7,
+            PartialWave::Pcm(bank, sample) => ((*bank as u16) << 1) << 7 | *sample as u16
+        }
+    }
+}
+
+// picks a new wave for each of a tone's partials as the alternator silences it. the partials sharing a
+// structure fade together, so the structure can follow their choice of synth or PCM: a pair can be
+// synth and synth, PCM and synth, or PCM and PCM, but not synth then PCM without ring modulation
+pub struct D110WaveSelector {
+    name: String,
+    waves: [PartialWave; 4], // in the order of the partials in the tone
+    rng: StdRng
+}
+
+impl D110WaveSelector {
+    // bank 1 and 2 samples to switch between
+    const PCM_SAMPLES: [(u8, u8); 10] = [(0, 40), (0, 52), (0, 58), (0, 64), (0, 77), (0, 100), (1, 8), (1, 24), (1, 40), (1, 56)];
+
+    const SS: u8 = 0;
+    const PS: u8 = 2;
+    const PP: u8 = 5;
+
+    pub fn new(name: &str, rng: StdRng) -> D110WaveSelector {
+        let mut selector = D110WaveSelector {
+            name: name.to_string(),
+            waves: [PartialWave::Square; 4],
+            rng
+        };
+        for at in 0..4 {
+            selector.choose(at);
+        }
+        selector
+    }
+
+    // the partial numbered by the last digit of its prefix
+    fn position(idx: u8) -> usize {
+        PARTIAL_PREFIXES.iter().position(|p| p.ends_with(char::from(b'0' + idx))).unwrap()
+    }
+
+    fn choose(&mut self, at: usize) {
+        let pcm = (at == 0 || at == 2 || matches!(self.waves[at - 1], PartialWave::Pcm(_, _))) && self.rng.gen_bool(0.5);
+        self.waves[at] = if pcm {
+            let (bank, sample) = *D110WaveSelector::PCM_SAMPLES.choose(&mut self.rng).unwrap();
+            PartialWave::Pcm(bank, sample)
+        } else {
+            *[PartialWave::Square, PartialWave::Saw].choose(&mut self.rng).unwrap()
+        };
+        // the second of the pair fades with the first, so can follow it back to synth
+        if (at == 0 || at == 2) && !pcm && matches!(self.waves[at + 1], PartialWave::Pcm(_, _)) {
+            self.choose(at + 1);
+        }
+    }
+
+    pub fn structures(&self) -> [u8; 2] {
+        let pair = |first: PartialWave, second: PartialWave| match (first, second) {
+            (PartialWave::Pcm(_, _), PartialWave::Pcm(_, _)) => D110WaveSelector::PP,
+            (PartialWave::Pcm(_, _), _) => D110WaveSelector::PS,
+            _ => D110WaveSelector::SS
+        };
+        [pair(self.waves[0], self.waves[1]), pair(self.waves[2], self.waves[3])]
+    }
+}
+
+impl Selector for D110WaveSelector {
+    fn next1(&mut self) {
+        self.next(1);
+    }
+
+    fn next2(&mut self) {
+        self.next(2);
+    }
+
+    fn next(&mut self, idx: u8) {
+        let at = D110WaveSelector::position(idx);
+        self.choose(at);
+        println!("{} partial {} now {:?}", self.name, PARTIAL_PREFIXES[at], self.waves[at]);
+    }
+
+    fn val(&self, idx: u8) -> u16 {
+        self.waves[D110WaveSelector::position(idx)].value()
+    }
+}
+
+
 // typedef enum t_partialConfig { ss = 0, ss_r, ps, ps_r, sp_r, pp, pp_r, s_s, p_p, ss_r_noDry, ps_r_noDry, sp_r_noDry, pp_r_noDry };
 
 
-pub const PARTIAL_SPEC: [Updater; 58] = [
+pub const PARTIAL_SPEC: [Updater; 57] = [
     Updater::Const("pitchCoarse", 36),
     Updater::Sweep("pitchFine", 40, 60), // 0-100 -> +/- 50
     Updater::Const("keyFollowPitch", 11),
     Updater::Const("allowPitchBend", 1),
-    Updater::Const("waveSource", 0), // use SelectOnZero
-    Updater::Const("pcmSource", 77), // use SelectOnZero
+    Updater::SelectOnZero("wave"), // waveSource and pcmSource
     Updater::Sweep("wavePulseWidth", 20, 80), // 0-100
     Updater::Const("wavePulseWidthVelocityMod", 11), // 0-14 -->
     Updater::Sweep("pitchEnvelopeDepth", 1, 5), // 1-100
@@ -215,3 +759,342 @@ pub const PARTIAL_SPEC: [Updater; 58] = [
     Updater::Sweep("env_amplitude_attack2Level", 10, 90),
     Updater::Sweep("env_amplitude_sustainLevel", 45, 55)
 ];
+
+// the MT-32 and CM-32L partials take the same values
+pub const PARTIAL_LIMITS: ParamLimits = ParamLimits {
+    device: "D-110",
+    params: &[
+        ("pitchCoarse", 0, 96), ("pitchFine", 0, 100), ("keyFollowPitch", 0, 16), ("allowPitchBend", 0, 1),
+        ("waveSource", 0, 3), ("pcmSource", 0, 127), ("wavePulseWidth", 0, 100), ("wavePulseWidthVelocityMod", 0, 14),
+        ("pitchEnvelopeDepth", 0, 10), ("pitchEnvelopeVelocityMod", 0, 3), ("pitchEnvelopeTimeKeyFollow", 0, 4),
+        ("env_pitch_attackTime", 0, 100), ("env_pitch_decayTime", 0, 100), ("env_pitch_slopeTime", 0, 100), ("env_pitch_releaseTime", 0, 100),
+        ("env_pitch_startLevel", 0, 100), ("env_pitch_attackLevel", 0, 100), ("env_pitch_breakPoint", 0, 100),
+        ("env_pitch_sustainLevel", 0, 100), ("env_pitch_releaseLevel", 0, 100),
+        ("pitchLfoRate", 0, 100), ("pitchLfoDepth", 0, 100), ("pitchLfoModSens", 0, 100),
+        ("envFilterFreq", 0, 100), ("envFilterReso", 0, 30), ("envFilterFreqKeyFollow", 0, 16),
+        ("envFilterBiasPoint", 0, 127), ("envFilterBiasLevel", 0, 14), ("envFilterDepth", 0, 100), ("envFilterVelocityMod", 0, 100),
+        ("envFilterDepthKeyFollow", 0, 4), ("envFilterTimeKeyFollow", 0, 4),
+        ("env_filter_attackTime", 0, 100), ("env_filter_decayTime", 0, 100), ("env_filter_attack2Time", 0, 100),
+        ("env_filter_slopeTime", 0, 100), ("env_filter_releaseTime", 0, 100),
+        ("env_filter_attackLevel", 0, 100), ("env_filter_breakPoint", 0, 100), ("env_filter_attack2Level", 0, 100), ("env_filter_sustainLevel", 0, 100),
+        ("vol", 0, 100), ("amplEnvVelocityMod", 0, 100),
+        ("amplEnvLowerBiasPoint", 0, 127), ("amplEnvLowerBiasLevel", 0, 12), ("amplEnvUpperBiasPoint", 0, 127), ("amplEnvUpperBiasLevel", 0, 12),
+        ("amplEnvTimeKeyFollow", 0, 4), ("amplEnvAttackTimeKeyFollow", 0, 4),
+        ("env_amplitude_attackTime", 0, 100), ("env_amplitude_decayTime", 0, 100), ("env_amplitude_attack2Time", 0, 100),
+        ("env_amplitude_slopeTime", 0, 100), ("env_amplitude_releaseTime", 0, 100),
+        ("env_amplitude_attackLevel", 0, 100), ("env_amplitude_breakPoint", 0, 100), ("env_amplitude_attack2Level", 0, 100), ("env_amplitude_sustainLevel", 0, 100)
+    ]
+};
+
+
+// for each key, so the rhythm part's sounds and their places move with the system settings
+pub const RHYTHM_SPEC: [Updater; 4] = [
+    Updater::Sweep("tone", 64, 127), // the rhythm PCM sounds
+    Updater::Const("level", 80),
+    Updater::Sweep("pan", 0, 14),
+    Updater::Const("output", 1) // reverb, on the mix outputs of the D-110
+];
+
+// swept far more slowly than the partials, see modulate_d110
+pub const SYSTEM_SPEC: [Updater; 12] = [
+    Updater::Sweep("reverbMode", 1, 8), // 1-3 on the MT-32 and CM-32L, see DeviceProfile::system_spec
+    Updater::Sweep("reverbTime", 0, 7),
+    Updater::Sweep("reverbLevel", 0, 5),
+    Updater::Sweep("partialReserve1", 2, 6), // the modulated parts
+    Updater::Sweep("partialReserve2", 2, 6),
+    Updater::Sweep("partialReserve3", 2, 6),
+    Updater::Const("partialReserve4", 4),
+    Updater::Const("partialReserve5", 4),
+    Updater::Const("partialReserve6", 4),
+    Updater::Const("partialReserve7", 0),
+    Updater::Const("partialReserve8", 0),
+    Updater::Const("partialReserveRhythm", 0) // see reserve_rhythm_partials
+];
+
+const PARTIALS: i16 = 32;
+const RHYTHM_RESERVE: i8 = 2; // what the modulated parts leave
+
+// the drum map of --d110-rhythm needs partials kept for it, so a spec that reserves none gets some
+pub fn reserve_rhythm_partials(spec: &mut [Updater]) {
+    for u in spec.iter_mut() {
+        if let Updater::Const("partialReserveRhythm", 0) = u {
+            *u = Updater::Const("partialReserveRhythm", RHYTHM_RESERVE);
+        }
+    }
+}
+
+// the reserves can't add up to more partials than there are, wherever the sweeps are; the
+// alternator and selectors could take them anywhere, so they aren't allowed
+pub fn check_partial_reserve(spec: &[Updater]) -> Result<(), String> {
+    let most = spec.iter().filter(|u| u.name().starts_with("partialReserve")).map(|u| match u {
+        Updater::Const(_, c) | Updater::PairedInverseConst(_, c) => Ok(*c as i16),
+        Updater::Sweep(_, _, max) | Updater::ShapedSweep(_, _, max, _) | Updater::WideSweep(_, _, max) => Ok(*max),
+        Updater::PairedInverseSweep(n) | Updater::SelectOnZero(n) => Err(format!("{} needs a fixed number of partials or a sweep", n))
+    }).sum::<Result<i16, String>>()?;
+    if most > PARTIALS {
+        return Err(format!("the partial reserves reach {} but there are only {} partials", most, PARTIALS));
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test_data_request {
+    use super::{address_of, set_up_tone, D110Reply, D110ReplyError, D110SysEx, RhythmKey, TONE_SIZE, D110};
+
+    #[test]
+    fn builds_rq1_with_seven_bit_size_and_checksum() {
+        let request = D110SysEx::request(address_of(2), TONE_SIZE);
+
+        assert_eq!(request.to_send(), [0xF0, 0x41, 0x10, 0x16, 0x11, 0x04, 0x01, 0x76, 0x00, 0x01, 0x76, 0x0E, 0xF7]);
+    }
+
+    #[test]
+    fn checksum_of_multiple_of_128_is_zero() {
+        let request = D110SysEx::request(0x100000, 0x70);
+
+        assert_eq!(request.to_send()[11], 0);
+    }
+
+    #[test]
+    fn decodes_tone_header_and_partial_fields() {
+        let mut tone = set_up_tone(2);
+        tone.data_vec_u8([36, 55].to_vec());
+
+        let reply = D110Reply::parse(&tone.to_send()).unwrap();
+        let values = reply.values();
+
+        assert_eq!(reply.address, 0x040176);
+        assert_eq!(reply.tone_name(2), Some(String::from("tone2")));
+        assert_eq!(values[2], (String::from("tone2_partialEnable"), 0xF));
+        assert_eq!(values[5], (String::from("tone2_partialA_1_pitchFine"), 55));
+    }
+
+    #[test]
+    fn decodes_timbre_fields() {
+        let reply = D110Reply::parse(&D110.init_part(3).to_send()).unwrap();
+        let values = reply.values();
+
+        assert_eq!(values[0], (String::from("timbre3_toneGroup"), 2));
+        assert_eq!(values[1], (String::from("timbre3_toneNumber"), 61));
+        assert_eq!(values.len(), 12);
+    }
+
+    #[test]
+    fn decodes_rhythm_key_fields() {
+        let reply = D110Reply::parse(&D110.set_up_rhythm_keys(&RhythmKey::defaults()[..2]).to_send()).unwrap();
+
+        assert_eq!(reply.address, 0x030110);
+        assert_eq!(reply.values(), [
+            (String::from("key24_tone"), 64), (String::from("key24_level"), 80), (String::from("key24_pan"), 7), (String::from("key24_output"), 1),
+            (String::from("key25_tone"), 65), (String::from("key25_level"), 80), (String::from("key25_pan"), 7), (String::from("key25_output"), 1)
+        ]);
+    }
+
+    #[test]
+    fn rejects_bad_checksum_and_other_commands() {
+        let mut msg = D110.init_part(1).to_send();
+        let request = D110SysEx::request(0x030000, 0x10).to_send();
+        msg[8] += 1;
+
+        assert_eq!(D110Reply::parse(&msg).err(), Some(D110ReplyError::Checksum));
+        assert_eq!(D110Reply::parse(&request).err(), Some(D110ReplyError::NotD110Dt1));
+        assert_eq!(D110Reply::parse(&msg[..5]).err(), Some(D110ReplyError::Framing));
+    }
+}
+
+
+#[cfg(test)]
+mod test_memory {
+    use super::{D110Memory, D110Reply, D110SysEx, set_up_tone, PARTIAL_SPEC};
+    use crate::modulation::{SysExComposer, Updater};
+
+    // a tone with one partial, its sweeps at 50 apart from those given
+    fn tone(sweeps: &[(&str, i8)]) -> D110SysEx {
+        let mut tone = set_up_tone(2);
+        for u in PARTIAL_SPEC {
+            match u {
+                Updater::Const(_, c) => tone.data(c),
+                Updater::SelectOnZero(_) => tone.data_double_byte(77),
+                _ => tone.data(sweeps.iter().find(|(n, _)| *n == u.name()).map_or(50, |(_, v)| *v))
+            }
+        }
+        tone
+    }
+
+    fn written(changes: &[D110SysEx]) -> Vec<D110Reply> {
+        changes.iter().map(|c| D110Reply::parse(&c.to_send()).unwrap()).collect()
+    }
+
+    #[test]
+    fn sends_the_whole_block_first_and_nothing_when_unchanged() {
+        let mut memory = D110Memory::new();
+
+        let first = memory.changes(&tone(&[]));
+
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].to_send(), tone(&[]).to_send());
+        assert!(memory.changes(&tone(&[])).is_empty());
+    }
+
+    #[test]
+    fn writes_changed_runs_at_their_own_addresses() {
+        let mut memory = D110Memory::new();
+        memory.record(&tone(&[]));
+
+        let changes = written(&memory.changes(&tone(&[("pitchFine", 41), ("env_filter_sustainLevel", 46)])));
+
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].address, 0x040205); // pitchFine of tone 2's first partial
+        assert_eq!(changes[0].data, [41]);
+        assert_eq!(changes[1].values(), [(String::from("tone2_partialA_1_env_filter_sustainLevel"), 46)]);
+    }
+
+    #[test]
+    fn coalesces_changes_closer_than_a_new_message() {
+        let mut memory = D110Memory::new();
+        memory.record(&tone(&[]));
+
+        let changes = written(&memory.changes(&tone(&[("pitchFine", 41), ("env_pitch_attackTime", 3)])));
+
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].data.len(), 11);
+        assert_eq!((changes[0].data[0], changes[0].data[10]), (41, 3));
+    }
+
+    #[test]
+    fn addresses_runs_across_a_seven_bit_boundary() {
+        let mut memory = D110Memory::new();
+        let mut tone_1 = D110SysEx::write(0x040000, &[0; 140]);
+        memory.record(&tone_1);
+        tone_1 = D110SysEx::write(0x040000, &[[0; 130].as_slice(), &[1]].concat());
+
+        assert_eq!(written(&memory.changes(&tone_1))[0].address, 0x040102);
+    }
+}
+
+
+#[cfg(test)]
+mod test_profiles {
+    use super::{check_partial_reserve, reserve_rhythm_partials, DeviceProfile, D110Reply, D110, MT32, CM32L, RhythmKey, SYSTEM_SPEC};
+    use crate::modulation::Updater;
+
+    #[test]
+    fn finds_profiles_by_name() {
+        assert_eq!(DeviceProfile::named("mt32").unwrap().name, "MT-32");
+        assert_eq!(DeviceProfile::named("CM-32L").unwrap().name, "CM-32L");
+        assert_eq!(DeviceProfile::named("d110").unwrap().name, "D-110");
+        assert!(DeviceProfile::named("d50").is_none());
+    }
+
+    #[test]
+    fn mt32_parts_have_no_outputs_or_key_ranges() {
+        let d110 = D110Reply::parse(&D110.init_part(2).to_send()).unwrap();
+        let mt32 = D110Reply::parse(&MT32.init_part(2).to_send()).unwrap();
+
+        assert_eq!(d110.address, mt32.address);
+        assert_eq!(d110.data[6], 3);
+        assert_eq!(mt32.data[6], 0);
+        assert_eq!(mt32.data[11], 0);
+        assert_eq!(mt32.data.len(), 16);
+    }
+
+    #[test]
+    fn only_the_d110_dates_its_system_area_and_only_the_mt32_has_a_display() {
+        let d110 = D110Reply::parse(&D110.init_system().to_send()).unwrap();
+        let mt32 = D110Reply::parse(&MT32.init_system().to_send()).unwrap();
+
+        assert_eq!(mt32.data.len(), 21);
+        assert_eq!(d110.data.len(), 32);
+        assert_eq!(mt32.data[0], 0);
+        assert_eq!(MT32.setup().len(), D110.setup().len() + 1);
+        assert_eq!(CM32L.setup().len(), D110.setup().len());
+
+        let display = D110Reply::parse(&MT32.display_message("hello").unwrap().to_send()).unwrap();
+        assert_eq!(display.address, 0x200000);
+        assert_eq!(display.data.len(), 20);
+    }
+
+    #[test]
+    fn system_spec_is_clamped_to_the_mt32_reverb_modes() {
+        assert!(D110.system_limits.check(&SYSTEM_SPEC).is_empty());
+        assert_eq!(MT32.system_limits.check(&SYSTEM_SPEC), ["reverbMode sweeps 1 to 8 but the MT-32 takes 0 to 3"]);
+
+        let mut spec = SYSTEM_SPEC.to_vec();
+        CM32L.system_limits.clamp(&mut spec);
+        assert!(matches!(spec[0], Updater::Sweep("reverbMode", 1, 3)));
+        assert!(matches!(CM32L.system_spec()[0], Updater::Sweep("reverbMode", 1, 3)));
+    }
+
+    #[test]
+    fn partial_reserves_never_exceed_the_partials() {
+        assert!(check_partial_reserve(&SYSTEM_SPEC).is_ok());
+
+        let mut spec = SYSTEM_SPEC.to_vec();
+        spec[9] = Updater::Sweep("partialReserve7", 0, 8);
+        assert_eq!(check_partial_reserve(&spec).unwrap_err(), "the partial reserves reach 38 but there are only 32 partials");
+
+        spec[9] = Updater::PairedInverseSweep("partialReserve7");
+        assert_eq!(check_partial_reserve(&spec).unwrap_err(), "partialReserve7 needs a fixed number of partials or a sweep");
+    }
+
+    #[test]
+    fn reserves_partials_for_the_rhythm_keys_within_the_partials() {
+        let mut spec = SYSTEM_SPEC.to_vec();
+        reserve_rhythm_partials(&mut spec);
+        assert!(matches!(spec[11], Updater::Const("partialReserveRhythm", 2)));
+        assert!(check_partial_reserve(&spec).is_ok());
+
+        spec[11] = Updater::Const("partialReserveRhythm", 8);
+        reserve_rhythm_partials(&mut spec);
+        assert!(check_partial_reserve(&spec).is_err());
+    }
+
+    #[test]
+    fn only_the_d110_assigns_rhythm_keys_to_outputs() {
+        let keys = [RhythmKey { tone: 64, level: 80, pan: 7, output: 4 }];
+        assert_eq!(D110Reply::parse(&D110.set_up_rhythm_keys(&keys).to_send()).unwrap().data, [64, 80, 7, 4]);
+        assert_eq!(D110Reply::parse(&MT32.set_up_rhythm_keys(&keys).to_send()).unwrap().data, [64, 80, 7, 1]);
+        assert_eq!(MT32.rhythm_limits.check(&[Updater::Const("output", 4)]), ["output is 4 but the MT-32 takes 0 to 1"]);
+    }
+}
+
+
+#[cfg(test)]
+mod test_waves {
+    use super::{set_up_tone, D110Reply, D110WaveSelector, PartialWave};
+    use crate::modulation::Selector;
+    use rand::{SeedableRng, rngs::StdRng};
+
+    #[test]
+    fn keeps_each_pair_to_a_structure_the_d110_has() {
+        let mut waves = D110WaveSelector::new("tone1", StdRng::seed_from_u64(1));
+        for n in 0..200 {
+            waves.next(n % 4 + 1);
+            for pair in waves.waves.chunks(2) {
+                assert!(!matches!(pair, [PartialWave::Square | PartialWave::Saw, PartialWave::Pcm(_, _)]));
+            }
+            assert!(waves.structures().iter().all(|s| [0, 2, 5].contains(s)));
+        }
+    }
+
+    #[test]
+    fn writes_the_wave_and_pcm_source_bytes() {
+        let mut waves = D110WaveSelector::new("tone1", StdRng::seed_from_u64(1));
+        waves.waves = [PartialWave::Pcm(1, 24), PartialWave::Saw, PartialWave::Square, PartialWave::Pcm(0, 77)];
+
+        assert_eq!(waves.val(1), 2 << 7 | 24); // bank 2
+        assert_eq!(waves.val(3), 1 << 7);
+        assert_eq!(waves.val(4), 77);
+        assert_eq!(waves.structures(), [2, 0]);
+    }
+
+    #[test]
+    fn sets_the_structures_in_the_tone_header() {
+        let mut tone = set_up_tone(1);
+        tone.set_structures([2, 5]);
+
+        let written = D110Reply::parse(&tone.to_send()).unwrap();
+        assert_eq!(written.values()[0..2], [(String::from("tone1_structure12"), 2), (String::from("tone1_structure34"), 5)]);
+    }
+}
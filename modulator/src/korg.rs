@@ -2,9 +2,12 @@
 use crate::modulation::{
     SysExComposer,
     Selector,
-    Updater
+    Updater,
+    Lfo,
+    Shape
 };
-use rand::prelude::SliceRandom;
+use crate::spec::{SpecFile, ParamLimits, updaters_from};
+use rand::{SeedableRng, rngs::StdRng, seq::SliceRandom};
 
 pub const CHANNEL: u8 = 0;
 
@@ -53,6 +56,213 @@ impl SysExComposer for KorgProgramSysEx {
 }
 
 
+impl KorgProgramSysEx {
+    // name, program block, both oscillators and the effects; the rest of data is padding
+    pub const PROGRAM_SIZE: usize = 164;
+
+    pub fn from_dump(dump: &[u8]) -> Option<KorgProgramSysEx> {
+        let mut s = KorgProgramSysEx::new();
+        if dump.len() != s.data.len() || dump[..5] != s.data[..5] || dump[dump.len() - 1] != 0xF7 {
+            return None;
+        }
+        s.data.copy_from_slice(dump);
+        s.pos = s.data.len();
+        Some(s)
+    }
+
+    // the program bytes with their MSBs restored, as the 05R/W stores them
+    pub fn program(&self) -> Vec<u8> {
+        let mut p = decode_7_in_8(&self.data[5..self.data.len() - 1]);
+        p.truncate(KorgProgramSysEx::PROGRAM_SIZE);
+        p
+    }
+}
+
+fn encode_7_in_8(raw: &[u8]) -> Vec<u8> {
+    raw.chunks(7).flat_map(|chunk| {
+        let carry = chunk.iter().enumerate().fold(0, |c, (i, b)| c | ((b & 0x80) >> (7 - i)));
+        std::iter::once(carry).chain(chunk.iter().map(|b| b & 0x7F))
+    }).collect()
+}
+
+fn decode_7_in_8(encoded: &[u8]) -> Vec<u8> {
+    encoded.chunks(8).flat_map(|chunk| {
+        chunk[1..].iter().enumerate().map(move |(i, b)| b | ((chunk[0] << (7 - i)) & 0x80))
+    }).collect()
+}
+
+
+// captured programs gathered into an all program dump, loaded into the 05R/W as a bank of presets
+pub struct KorgProgramBank {
+    programs: Vec<Vec<u8>>
+}
+
+impl KorgProgramBank {
+    pub const PROGRAMS: usize = 100;
+
+    pub fn new() -> KorgProgramBank {
+        KorgProgramBank {
+            programs: Vec::new()
+        }
+    }
+
+    pub fn add(&mut self, program: &KorgProgramSysEx) -> Result<(), String> {
+        if self.is_full() {
+            return Err(format!("bank already holds {} programs", KorgProgramBank::PROGRAMS));
+        }
+        self.programs.push(program.program());
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.programs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.programs.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() == KorgProgramBank::PROGRAMS
+    }
+
+    // a bank that isn't full repeats its programs in order to fill the remaining slots
+    pub fn sys_ex(&self, bank: u8) -> Option<Vec<u8>> {
+        if self.is_empty() {
+            return None;
+        }
+        let raw = self.programs.iter().cycle().take(KorgProgramBank::PROGRAMS).flatten().copied().collect::<Vec<u8>>();
+        let mut data = vec![0xF0,
+                            0x42, // ID of Korg
+                            0x30 | CHANNEL, // format ID (3), channel
+                            0x36, // 05R/W ID
+                            0x4C, // all program parameter dump
+                            bank & 0x01]; // 0: A, 1: B
+        data.extend(encode_7_in_8(&raw));
+        data.push(0xF7);
+        Some(data)
+    }
+}
+
+
+pub struct KorgProgramDumpRequest {
+    pub data: [u8; 6]
+}
+
+impl KorgProgramDumpRequest {
+    pub fn new() -> KorgProgramDumpRequest {
+        KorgProgramDumpRequest {
+            data: [0xF0,
+                   0x42, // ID of Korg
+                   0x30 | CHANNEL, // format ID (3), channel
+                   0x36, // 05R/W ID
+                   0x10, // program parameter dump request
+                   0xF7]
+        }
+    }
+}
+
+
+// the inverse of KorgProgramSysEx::data, reading back each byte with its carried MSB
+struct KorgProgramReader<'a> {
+    pos: usize,
+    data: &'a [u8]
+}
+
+impl KorgProgramReader<'_> {
+    fn new(data: &[u8]) -> KorgProgramReader<'_> {
+        KorgProgramReader {
+            pos: 1,
+            data
+        }
+    }
+
+    fn next(&mut self) -> i8 {
+        let shift: usize = 7 - (self.pos - 1) % 8;
+        let block_idx: usize = 8 * (self.pos / 8);
+        let carry: u8 = (self.data[block_idx + 5] << shift) & 0x80;
+        let v = (self.data[self.pos + 5] & 0x7F) | carry;
+        self.pos += if shift == 1 { 2 } else { 1 };
+        v as i8
+    }
+
+    fn next_double_byte(&mut self) -> i16 {
+        let lsb = self.next() as u8 as u16;
+        let msb = self.next() as u8 as u16;
+        (lsb | (msb << 8)) as i16
+    }
+
+    fn name(&mut self, len: usize) -> String {
+        (0..len).map(|_| self.next() as u8 as char).collect::<String>().trim_end().to_string()
+    }
+
+    fn next_value(&mut self, u: &Updater) -> i16 {
+        match u {
+            _ if param_width(u) == 2 => self.next_double_byte(),
+            Updater::Sweep(_, min, _) | Updater::ShapedSweep(_, min, _, _) if *min >= 0 => self.next() as u8 as i16,
+            _ => self.next() as i16
+        }
+    }
+
+    fn read(&mut self, updaters: &[Updater], prefix: Option<&str>, values: &mut Vec<(String, i16)>) {
+        for u in updaters {
+            let v = self.next_value(u);
+            if !u.name().is_empty() {
+                let key = match prefix {
+                    Some(p) => [p, u.name()].join("_"),
+                    None => u.name().to_string()
+                };
+                values.push((key, v));
+            }
+        }
+    }
+}
+
+
+pub struct KorgProgram {
+    pub name: String,
+    pub values: Vec<(String, i16)>
+}
+
+impl KorgProgram {
+    pub const NAME_LENGTH: usize = 10;
+
+    // the effects are those of the session, as the dump only has their numbers
+    pub fn from_dump(dump: &[u8], effects: &[Effect]) -> Result<KorgProgram, String> {
+        KorgProgramSysEx::from_dump(dump).ok_or(format!("unexpected {} byte reply", dump.len()))?;
+
+        let mut reader = KorgProgramReader::new(dump);
+        let name = reader.name(KorgProgram::NAME_LENGTH);
+        let mut values = Vec::new();
+        reader.read(&PROGRAM_SPEC, None, &mut values);
+        reader.read(&OSC_SPEC, Some("osc1"), &mut values);
+        reader.read(&OSC_SPEC, Some("osc2"), &mut values);
+        reader.read(&KorgEffectSelector::new(effects, StdRng::seed_from_u64(0)).pre_eff(), None, &mut values);
+
+        let mut program = KorgProgram { name, values };
+        for prefix in ["eff1", "eff2"] {
+            let number = program.value(&format!("{}_number", prefix)).unwrap_or(0);
+            let eff = effects.iter().find(|e| e.number as i16 == number)
+                .ok_or(format!("{} number {} is not one of the effects in use", prefix, number))?;
+            reader.read(&eff.updater, Some(prefix), &mut program.values);
+        }
+        Ok(program)
+    }
+
+    pub fn value(&self, key: &str) -> Option<i16> {
+        self.values.iter().find(|(k, _)| k == key).map(|(_, v)| *v)
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        let mut j = json::object!{ name: self.name.clone() };
+        for (k, v) in &self.values {
+            j["values"][k.as_str()] = (*v).into();
+        }
+        j
+    }
+}
+
+
 pub struct KorgInitSysEx {
     pub data: [u8; 8]
 }
@@ -78,24 +288,136 @@ pub struct KorgSingleParamSysEx {
 
 
 impl KorgSingleParamSysEx {
-    pub fn new(p: u8, v: u8) -> KorgSingleParamSysEx {
+    // negative values go as 14 bit two's complement
+    pub fn new(p: u16, v: i16) -> KorgSingleParamSysEx {
         KorgSingleParamSysEx {
             data: [0xF0,
                    0x42, // ID of Korg
                    0x30 | CHANNEL, // format ID (3), channel
                    0x36, // 05R/W ID
                    0x41, // parameter change
-                   p & 0x7F, // lsb parameter #
-                   (p >> 7) & 0x7F, // msb
-                   v & 0x7F, // lsb value
-                   (v >> 7) & 0x7F, // msb
+                   (p & 0x7F) as u8, // lsb parameter #
+                   ((p >> 7) & 0x7F) as u8, // msb
+                   (v & 0x7F) as u8, // lsb value
+                   ((v >> 7) & 0x7F) as u8, // msb
                    0xF7]
         }
     }
 }
 
-pub const ENV_TIME_LOW: i8 = 1;
-pub const ENV_TIME_HIGH: i8 = 10;
+
+// the 05R/W's number for each parameter a single parameter change can set, which is its byte in the
+// program after the name (oscillatorMode is 0). The bit packed parameters and the effects have none,
+// so a change to any of them goes as a whole program
+const PROGRAM_PARAMS: [(&str, u16); 27] = [
+    ("oscillatorMode", 0), ("noteMode", 1), ("osc1", 2), ("osc1Register", 4), ("osc2", 5), ("osc2Register", 7),
+    ("octave", 8), ("detune", 9), ("delay", 10),
+    ("env_pitch_startLevel", 11), ("env_pitch_attackTime", 12), ("env_pitch_attackLevel", 13),
+    ("env_pitch_decayTime", 14), ("env_pitch_releaseTime", 15), ("env_pitch_releaseLevel", 16),
+    ("pitchEgTimeVelocitySens", 17), ("pitchEgLevelVelocitySens", 18),
+    ("modFreq", 20), ("modDelay", 21), ("modIntensity", 22),
+    ("pitchBendRange", 23), ("vdfCutoff", 24), ("vdfModulationIntensity", 25), ("vdaAmplitude", 26),
+    ("joystickPitchBendRange", 27), ("joystickVdfSweepIntensity", 28), ("joystickVdfModulationIntensity", 29)
+];
+
+// counted from the start of each oscillator's block
+const OSC_PARAMS: [(&str, u16); 41] = [
+    ("pitchEgIntensity", 0), ("pitchEgFreq", 2), ("pitchEgDelay", 3), ("pitchEgFadeIn", 4),
+    ("pitchModulationIntensity", 5), ("pitchFreqModKeyTracking", 6), ("pitchModIntensityAftertouch", 7),
+    ("pitchModIntensityJoystick", 8), ("pitchFreqModAftertouchJoystick", 9),
+    ("vdfCutoff", 10), ("vdfCutoffKeybTrackKey", 11), ("vdfCutoffKeybTrackIntensity", 12), ("vdfEgIntensity", 13),
+    ("vdfEgTimeKeybTrack", 14), ("vdfEgTimeVelocitySens", 15), ("vdfEgIntensityVelocitySens", 16),
+    ("env_filter_attackTime", 17), ("env_filter_attackLevel", 18), ("env_filter_decayTime", 19), ("env_filter_breakPoint", 20),
+    ("env_filter_slopeTime", 21), ("env_filter_sustainLevel", 22), ("env_filter_releaseTime", 23), ("env_filter_releaseLevel", 24),
+    ("vol", 25), ("oscKeybTrackKey", 26), ("amplKeybTrackKeyIntensity", 27), ("amplVelocitySens", 28),
+    ("amplEgTimeKeybTrack", 29), ("amplEgTimeVelocitySens", 30),
+    ("env_amplitude_attackTime", 31), ("env_amplitude_attackLevel", 32), ("env_amplitude_decayTime", 33),
+    ("env_amplitude_breakPoint", 34), ("env_amplitude_slopeTime", 35), ("env_amplitude_sustainLevel", 36),
+    ("env_amplitude_releaseTime", 37),
+    ("cdSend", 42), ("filterQ", 43), ("colourVelocitySens", 44), ("panCentre", 46)
+];
+const OSC_PARAMS_FROM: [u16; 2] = [30, 77];
+
+#[derive(Clone, Copy)]
+pub enum KorgBlock {
+    Program,
+    Osc(usize), // 0 or 1
+    Effects
+}
+
+impl KorgBlock {
+    pub fn param_number(&self, name: &str) -> Option<u16> {
+        let find = |params: &[(&str, u16)]| params.iter().find(|(n, _)| *n == name).map(|(_, p)| *p);
+        match self {
+            KorgBlock::Program => find(&PROGRAM_PARAMS),
+            KorgBlock::Osc(o) => find(&OSC_PARAMS).map(|p| OSC_PARAMS_FROM[*o] + p),
+            KorgBlock::Effects => None
+        }
+    }
+}
+
+// the offset into the program bytes and the width of each numbered parameter, as the 05R/W applies them
+#[cfg(test)]
+pub fn param_layout() -> std::collections::BTreeMap<u16, (usize, usize)> {
+    // the oscillator selections are the only double byte parameters
+    let width = |name: &str| if name == "osc1" || name == "osc2" { 2 } else { 1 };
+    PROGRAM_PARAMS.iter().map(|(n, p)| (*p, width(n)))
+        .chain(OSC_PARAMS_FROM.iter().flat_map(|from| OSC_PARAMS.iter().map(move |(_, p)| (from + p, 1))))
+        .map(|(p, w)| (p, (KorgProgram::NAME_LENGTH + p as usize, w)))
+        .collect()
+}
+
+
+// single parameter changes only reach the edit buffer, so these are for a 05R/W left in edit mode
+pub struct KorgParamDeltas {
+    sent: Option<Vec<i16>>,
+    since_dump: usize
+}
+
+impl KorgParamDeltas {
+    // a whole program now and then, in case a parameter change went astray
+    const RESYNC_EVERY: usize = 50;
+
+    pub fn new() -> KorgParamDeltas {
+        KorgParamDeltas {
+            sent: None,
+            since_dump: 0
+        }
+    }
+
+    // None when the program should be sent as a dump: the first, every RESYNC_EVERY after it, and
+    // whenever a parameter without a number changes, which includes any change of effect
+    pub fn diff(&mut self, program: &KorgProgramSysEx, blocks: &[(KorgBlock, &[Updater])]) -> Option<Vec<KorgSingleParamSysEx>> {
+        let mut reader = KorgProgramReader::new(&program.data);
+        reader.name(KorgProgram::NAME_LENGTH);
+        let mut values = Vec::new();
+        for (block, updaters) in blocks {
+            for u in *updaters {
+                values.push((block.param_number(u.name()), reader.next_value(u)));
+            }
+        }
+
+        let changes = self.sent.as_ref().filter(|_| self.since_dump < KorgParamDeltas::RESYNC_EVERY).and_then(|sent| {
+            values.iter().zip(sent)
+                .filter(|((_, v), s)| v != *s)
+                .map(|((p, v), _)| p.map(|p| KorgSingleParamSysEx::new(p, *v)))
+                .collect::<Option<Vec<_>>>()
+        });
+        self.since_dump = if changes.is_some() { self.since_dump + 1 } else { 0 };
+        self.sent = Some(values.into_iter().map(|(_, v)| v).collect());
+        changes
+    }
+}
+
+fn param_width(u: &Updater) -> usize {
+    match u {
+        Updater::SelectOnZero(_) | Updater::WideSweep(_, _, _) => 2,
+        _ => 1
+    }
+}
+
+pub const ENV_TIME_LOW: i16 = 1;
+pub const ENV_TIME_HIGH: i16 = 10;
 
 pub const PROGRAM_SPEC: [Updater; 28] = [
     Updater::Const("oscillatorMode", 1),
@@ -131,6 +453,21 @@ pub const PROGRAM_SPEC: [Updater; 28] = [
     Updater::Const("joystickVdfModulationIntensity", 0)
 ];
 
+// the bit packed parameters can be any byte
+pub const PROGRAM_LIMITS: ParamLimits = ParamLimits {
+    device: "05R/W",
+    params: &[
+        ("osc1Register", -128, 127), ("osc2Register", -128, 127), ("cutoffTypeDetails", -128, 127),
+        ("oscillatorMode", 0, 2), ("noteMode", 0, 1), ("octave", -1, 1), ("detune", -17, 17), ("delay", 0, 99),
+        ("env_pitch_startLevel", -99, 99), ("env_pitch_attackTime", 0, 99), ("env_pitch_attackLevel", -99, 99),
+        ("env_pitch_decayTime", 0, 99), ("env_pitch_releaseTime", 0, 99), ("env_pitch_releaseLevel", -99, 99),
+        ("pitchEgTimeVelocitySens", -99, 99), ("pitchEgLevelVelocitySens", -99, 99),
+        ("modFreq", 0, 99), ("modDelay", 0, 99), ("modIntensity", 0, 99),
+        ("pitchBendRange", -12, 12), ("vdfCutoff", -99, 99), ("vdfModulationIntensity", 0, 99), ("vdaAmplitude", -99, 99),
+        ("joystickPitchBendRange", -12, 12), ("joystickVdfSweepIntensity", -99, 99), ("joystickVdfModulationIntensity", 0, 99)
+    ]
+};
+
 pub const OSC_SPEC: [Updater; 47] = [
     Updater::Sweep("pitchEgIntensity", 1, 20),
     Updater::Const("pitchWaveform", 0), // bits 1-4 = waveform, bit7=key sync)
@@ -163,7 +500,7 @@ pub const OSC_SPEC: [Updater; 47] = [
     Updater::Const("amplVelocitySens", 11),
     Updater::Const("amplEgTimeKeybTrack", 50),
     Updater::Const("amplEgTimeVelocitySens", 10),
-    Updater::Sweep("env_amplitude_attackTime", 1, 10),
+    Updater::ShapedSweep("env_amplitude_attackTime", 1, 10, Lfo { shape: Shape::Cosine, phase: 0.5, follow: Some("env_filter_attackTime") }), // opens as the filter attack closes
     Updater::Sweep("env_amplitude_attackLevel", 60, 80),
     Updater::Sweep("env_amplitude_decayTime", ENV_TIME_LOW, ENV_TIME_HIGH),
     Updater::Sweep("env_amplitude_breakPoint", 40, 90),
@@ -181,11 +518,34 @@ pub const OSC_SPEC: [Updater; 47] = [
     Updater::Const("panCentre", 0x0F) // pan 0: A15, 0x0F: centre, 0x1E: B15
 ];
 
+// as with the program, the bit packed parameters can be any byte
+pub const OSC_LIMITS: ParamLimits = ParamLimits {
+    device: "05R/W",
+    params: &[
+        ("pitchWaveform", -128, 127), ("pitchFreqModAftertouchJoystick", -128, 127),
+        ("freq_EgTimeKeybTrackSwitchPolarity", -128, 127), ("freq_EgTimeVelocitySwitchPolarity", -128, 127),
+        ("ampl_EgTimeKeybTrackSwitchPolarity", -128, 127), ("ampl_EgTimeVelocitySwitchPolarity", -128, 127),
+        ("cdSend", -128, 127), ("vdfVdaKeyboardTrackMode", -128, 127),
+        ("pitchEgIntensity", -99, 99), ("pitchEgFreq", 0, 99), ("pitchEgDelay", 0, 99), ("pitchEgFadeIn", 0, 99),
+        ("pitchModulationIntensity", 0, 99), ("pitchFreqModKeyTracking", -99, 99), ("pitchModIntensityAftertouch", 0, 99), ("pitchModIntensityJoystick", 0, 99),
+        ("vdfCutoff", 0, 99), ("vdfCutoffKeybTrackKey", 0, 127), ("vdfCutoffKeybTrackIntensity", -99, 99), ("vdfEgIntensity", 0, 99),
+        ("vdfEgTimeKeybTrack", -99, 99), ("vdfEgTimeVelocitySens", -99, 99), ("vdfEgIntensityVelocitySens", -99, 99),
+        ("env_filter_attackTime", 0, 99), ("env_filter_attackLevel", -99, 99), ("env_filter_decayTime", 0, 99), ("env_filter_breakPoint", -99, 99),
+        ("env_filter_slopeTime", 0, 99), ("env_filter_sustainLevel", -99, 99), ("env_filter_releaseTime", 0, 99), ("env_filter_releaseLevel", -99, 99),
+        ("vol", 0, 99), ("oscKeybTrackKey", 0, 127), ("amplKeybTrackKeyIntensity", -99, 99), ("amplVelocitySens", -99, 99),
+        ("amplEgTimeKeybTrack", -99, 99), ("amplEgTimeVelocitySens", -99, 99),
+        ("env_amplitude_attackTime", 0, 99), ("env_amplitude_attackLevel", 0, 99), ("env_amplitude_decayTime", 0, 99), ("env_amplitude_breakPoint", 0, 99),
+        ("env_amplitude_slopeTime", 0, 99), ("env_amplitude_sustainLevel", 0, 99), ("env_amplitude_releaseTime", 0, 99),
+        ("filterQ", 0, 99), ("colourVelocitySens", -99, 99), ("panCentre", 0, 30)
+    ]
+};
+
 
 
 pub struct KorgOscSelector {
     osc1: u16,
-    osc2: u16
+    osc2: u16,
+    rng: StdRng
 }
 
 impl KorgOscSelector {
@@ -232,25 +592,33 @@ impl KorgOscSelector {
         ret
     }
 
-    fn random_osc() -> u16 {
-        *KorgOscSelector::expand(&KorgOscSelector::OSCILLATOR_RANGES).choose(&mut rand::thread_rng()).unwrap()
+    fn random_osc(rng: &mut StdRng) -> u16 {
+        *KorgOscSelector::expand(&KorgOscSelector::OSCILLATOR_RANGES).choose(rng).unwrap()
     }
 
-    pub fn new() -> KorgOscSelector {
+    pub fn new(mut rng: StdRng) -> KorgOscSelector {
         KorgOscSelector {
-            osc1: KorgOscSelector::random_osc(),
-            osc2: KorgOscSelector::random_osc()
+            osc1: KorgOscSelector::random_osc(&mut rng),
+            osc2: KorgOscSelector::random_osc(&mut rng),
+            rng
         }
     }
+
+    pub fn select(&mut self, osc1: u16, osc2: u16) {
+        self.osc1 = osc1;
+        self.osc2 = osc2;
+    }
 }
 
 impl Selector for KorgOscSelector {
     fn next1(&mut self) {
-        self.osc1 = KorgOscSelector::random_osc();
+        self.osc1 = KorgOscSelector::random_osc(&mut self.rng);
+        println!("osc1 change {}", self.osc1);
     }
 
     fn next2(&mut self) {
-        self.osc2 = KorgOscSelector::random_osc();
+        self.osc2 = KorgOscSelector::random_osc(&mut self.rng);
+        println!("osc2 change {}", self.osc2);
     }
 
     fn val(&self, at: u8) -> u16 {
@@ -266,12 +634,48 @@ impl Selector for KorgOscSelector {
 
 pub type FxUpdater<'a> = [Updater<'a>; 10];
 
+#[derive(Clone, Copy)]
 pub struct Effect<'a> {
     number: i8,
     mix: i8,
     pub updater: FxUpdater<'a>
 }
 
+impl<'a> Effect<'a> {
+    fn from_json(j: &'a json::JsonValue) -> Result<Effect<'a>, String> {
+        let number = j["number"].as_i8().ok_or(format!("effect without a number: {}", j.dump()))?;
+        let mix = j["mix"].as_i8().ok_or(format!("effect {}: mix missing or not an i8", number))?;
+        let updater = updaters_from(&j["updater"], &PHASER.updater).map_err(|e| format!("effect {}: {}", number, e))?;
+        Ok(Effect {
+            number,
+            mix,
+            updater: updater.try_into().map_err(|_| format!("effect {}: expected 10 updaters", number))?
+        })
+    }
+}
+
+// the selectors choose a different effect on each change so need at least two numbers to pick from
+fn check_distinct(effects: &[Effect]) -> Result<(), String> {
+    for (i, e) in effects.iter().enumerate() {
+        if effects[..i].iter().any(|other| other.number == e.number) {
+            return Err(format!("effect {} is listed more than once", e.number));
+        }
+    }
+    if effects.len() < 2 {
+        return Err(String::from("at least two different effects are needed"));
+    }
+    Ok(())
+}
+
+pub fn effects_from(spec: &SpecFile) -> Result<Vec<Effect<'_>>, String> {
+    let effects = match spec.json() {
+        Some(j) => j.members().map(Effect::from_json).collect::<Result<Vec<Effect>, String>>().map_err(|e| format!("{}: {}", spec.path, e))?,
+        None => AVAILABLE_EFFECTS.to_vec()
+    };
+    check_distinct(&effects).map_err(|e| format!("{}: {}", spec.path, e))?;
+    Ok(effects)
+}
+
 const PHASER: Effect = Effect {
     number: 32,
     mix: 50,
@@ -294,7 +698,7 @@ const TREMOLO: Effect = Effect {
     mix: 99,
     updater: [
         Updater::Sweep("tremoloDepth", 50, 99),
-        Updater::Sweep("tremoloSpeed", 64, 127), // should be 200 but only supporting i8 atm
+        Updater::Sweep("tremoloSpeed", 64, 200),
         Updater::Const("tremoloWaveform", 0), // 0: sine, 1: tri
         Updater::Sweep("tremoloWaveShape", -99, 99),
         Updater::Const("", 0),
@@ -325,7 +729,19 @@ const DISTORTION: Effect = Effect {
 };
 
 
-const AVAILABLE_EFFECTS: [Effect; 3] = [
+// the speeds run beyond 99 in finer steps
+pub const EFFECT_LIMITS: ParamLimits = ParamLimits {
+    device: "05R/W",
+    params: &[
+        ("phaserDepth", 0, 99), ("phaserSpeed", 1, 200), ("phaserWaveform", 0, 1), ("phaserFeedback", -99, 99), ("phaserManual", 0, 99),
+        ("tremoloDepth", 0, 99), ("tremoloSpeed", 1, 200), ("tremoloWaveform", 0, 1), ("tremoloWaveShape", -99, 99),
+        ("distDrive", 1, 111), ("distHotSpot", 0, 99), ("distResonance", 0, 99), ("distOut", 0, 99),
+        ("eff_modSource", 0, 5), ("eff_modAmount", -15, 15),
+        ("", 0, 0) // the unused bytes of an effect's block
+    ]
+};
+
+pub const AVAILABLE_EFFECTS: [Effect; 3] = [
     PHASER,
     TREMOLO,
     DISTORTION
@@ -333,18 +749,31 @@ const AVAILABLE_EFFECTS: [Effect; 3] = [
 
 
 pub struct KorgEffectSelector<'a> {
+    available: &'a [Effect<'a>],
+    rng: StdRng,
     pub eff1: &'a Effect<'a>,
     pub eff2: &'a Effect<'a>
 }
 
 impl <'a>KorgEffectSelector<'a> {
-    pub fn new() -> KorgEffectSelector<'a> {
+    pub fn new(available: &'a [Effect<'a>], mut rng: StdRng) -> KorgEffectSelector<'a> {
         KorgEffectSelector {
-            eff1: &AVAILABLE_EFFECTS.choose(&mut rand::thread_rng()).unwrap(),
-            eff2: &AVAILABLE_EFFECTS.choose(&mut rand::thread_rng()).unwrap()
+            available,
+            eff1: available.choose(&mut rng).unwrap(),
+            eff2: available.choose(&mut rng).unwrap(),
+            rng
         }
     }
 
+    pub fn numbered(&self, n: u16) -> Result<&'a Effect<'a>, String> {
+        self.available.iter().find(|e| e.number as u16 == n).ok_or(format!("effect {} is not available", n))
+    }
+
+    fn other_than(&mut self, current: &Effect) -> &'a Effect<'a> {
+        let others = self.available.iter().filter(|e| e.number != current.number).collect::<Vec<_>>();
+        others.choose(&mut self.rng).unwrap()
+    }
+
     pub fn pre_eff(&self) -> FxUpdater<'a> {
         [
             Updater::Const("", 0),
@@ -364,19 +793,13 @@ impl <'a>KorgEffectSelector<'a> {
 
 impl <'a>Selector for KorgEffectSelector<'a> {
     fn next1(&mut self) {
-        let new_eff = &AVAILABLE_EFFECTS.choose(&mut rand::thread_rng()).unwrap();
-        if new_eff.number == self.eff1.number {
-            self.next1();
-        }
-        self.eff1 = new_eff;
+        self.eff1 = self.other_than(self.eff1);
+        println!("new eff1 {}", self.eff1.number);
     }
 
     fn next2(&mut self) {
-        let new_eff = &AVAILABLE_EFFECTS.choose(&mut rand::thread_rng()).unwrap();
-        if new_eff.number == self.eff2.number {
-            self.next2();
-        }
-        self.eff2 = new_eff;
+        self.eff2 = self.other_than(self.eff2);
+        println!("new eff2 {}", self.eff2.number);
     }
 
     fn val(&self, idx: u8) -> u16 {
@@ -386,3 +809,258 @@ impl <'a>Selector for KorgEffectSelector<'a> {
         self.eff2.number as u16
     }
 }
+
+
+#[cfg(test)]
+mod test_program_dump {
+    use super::{KorgProgram, KorgProgramReader, KorgProgramSysEx, Updater, PROGRAM_SPEC, OSC_SPEC, AVAILABLE_EFFECTS, PHASER, TREMOLO};
+    use crate::modulation::SysExComposer;
+
+    fn encode_consts(sys_ex: &mut KorgProgramSysEx, updaters: &[Updater]) {
+        for u in updaters {
+            match u {
+                Updater::Const(_, c) => sys_ex.data(*c),
+                Updater::SelectOnZero(_) => sys_ex.data_double_byte(300),
+                Updater::Sweep(_, min, _) => sys_ex.data(*min as i8),
+                _ => sys_ex.data(5)
+            }
+        }
+    }
+
+    #[test]
+    fn reads_back_bytes_with_their_carried_msb() {
+        let mut sys_ex = KorgProgramSysEx::new();
+        let values: [i8; 10] = [1, -103, 127, -1, 0, 64, -128, 99, -17, 17];
+        for v in values {
+            sys_ex.data(v);
+        }
+        sys_ex.data_double_byte(337);
+
+        let mut reader = KorgProgramReader::new(&sys_ex.data);
+
+        assert_eq!(values.map(|_| reader.next()), values);
+        assert_eq!(reader.next_double_byte(), 337);
+    }
+
+    #[test]
+    fn rejects_a_dump_with_the_wrong_header_or_length() {
+        let mut sys_ex = KorgProgramSysEx::new();
+        assert_eq!(KorgProgram::from_dump(&sys_ex.data[..100], &AVAILABLE_EFFECTS).err().unwrap(), "unexpected 100 byte reply");
+
+        sys_ex.data[4] = 0x41;
+        assert!(KorgProgram::from_dump(&sys_ex.data, &AVAILABLE_EFFECTS).is_err());
+    }
+
+    fn tremolo_program() -> KorgProgramSysEx {
+        let mut sys_ex = KorgProgramSysEx::new();
+        sys_ex.name("2024-01-01");
+        encode_consts(&mut sys_ex, &PROGRAM_SPEC);
+        encode_consts(&mut sys_ex, &OSC_SPEC);
+        encode_consts(&mut sys_ex, &OSC_SPEC);
+        for v in [0, 36, 36, 99, 99, 99, 99, 101, 1, 0x1F] {
+            sys_ex.data(v);
+        }
+        encode_consts(&mut sys_ex, &TREMOLO.updater);
+        encode_consts(&mut sys_ex, &TREMOLO.updater);
+        sys_ex
+    }
+
+    #[test]
+    fn decodes_the_effects_of_the_session() {
+        let mut custom = TREMOLO;
+        custom.updater[0] = Updater::Sweep("shimmer", 0, 99);
+
+        let program = KorgProgram::from_dump(&tremolo_program().data, &[PHASER, custom]).unwrap();
+        assert_eq!(program.value("eff1_shimmer"), Some(50));
+        assert_eq!(KorgProgram::from_dump(&tremolo_program().data, &[PHASER, PHASER]).err().unwrap(), "eff1 number 36 is not one of the effects in use");
+    }
+
+    #[test]
+    fn decodes_named_values_including_the_selected_effects() {
+        let program = KorgProgram::from_dump(&tremolo_program().data, &AVAILABLE_EFFECTS).unwrap();
+
+        assert_eq!(program.name, "2024-01-01");
+        assert_eq!(program.value("oscillatorMode"), Some(1));
+        assert_eq!(program.value("osc2"), Some(300));
+        assert_eq!(program.value("detune"), Some(-17));
+        assert_eq!(program.value("osc1_vdfCutoffKeybTrackKey"), Some(64));
+        assert_eq!(program.value("osc2_panCentre"), Some(0x0F));
+        assert_eq!(program.value("eff1_number"), Some(36));
+        assert_eq!(program.value("eff_routing"), Some(0x1F));
+        assert_eq!(program.value("eff2_tremoloDepth"), Some(50));
+        assert_eq!(program.value("eff2_tremoloWaveShape"), Some(-99));
+        assert_eq!(program.value("eff2_eff_modAmount"), Some(0));
+    }
+}
+
+
+#[cfg(test)]
+mod test_program_bank {
+    use super::{KorgProgramSysEx, KorgProgramBank, encode_7_in_8, decode_7_in_8};
+    use crate::modulation::SysExComposer;
+
+    #[test]
+    fn encodes_the_same_way_as_the_composer() {
+        let mut sys_ex = KorgProgramSysEx::new();
+        let raw: Vec<u8> = (0..KorgProgramSysEx::PROGRAM_SIZE).map(|i| (i * 37) as u8).collect();
+        for b in &raw {
+            sys_ex.data(*b as i8);
+        }
+
+        let encoded = encode_7_in_8(&raw);
+
+        assert_eq!(encoded[..], sys_ex.data[5..5 + encoded.len()]);
+        assert_eq!(decode_7_in_8(&encoded), raw);
+        assert_eq!(sys_ex.program(), raw);
+    }
+
+    #[test]
+    fn fills_a_bank_by_repeating_its_programs() {
+        let mut bank = KorgProgramBank::new();
+        assert!(bank.sys_ex(0).is_none());
+
+        let mut first = KorgProgramSysEx::new();
+        first.name("first");
+        let mut second = KorgProgramSysEx::new();
+        second.name("second");
+        bank.add(&first).unwrap();
+        bank.add(&second).unwrap();
+
+        let dump = bank.sys_ex(1).unwrap();
+        let raw = decode_7_in_8(&dump[6..dump.len() - 1]);
+        let size = KorgProgramSysEx::PROGRAM_SIZE;
+
+        assert_eq!(dump[..6], [0xF0, 0x42, 0x30, 0x36, 0x4C, 0x01]);
+        assert_eq!(dump[dump.len() - 1], 0xF7);
+        assert_eq!(raw.len(), size * KorgProgramBank::PROGRAMS);
+        assert_eq!(raw[..size], first.program()[..]);
+        assert_eq!(raw[size..2 * size], second.program()[..]);
+        assert_eq!(raw[98 * size..99 * size], first.program()[..]);
+        assert_eq!(raw[99 * size..], second.program()[..]);
+    }
+
+    #[test]
+    fn refuses_more_than_a_bank_of_programs() {
+        let mut bank = KorgProgramBank::new();
+        for _ in 0..KorgProgramBank::PROGRAMS {
+            bank.add(&KorgProgramSysEx::new()).unwrap();
+        }
+
+        assert!(bank.is_full());
+        assert!(bank.add(&KorgProgramSysEx::new()).is_err());
+    }
+}
+
+
+#[cfg(test)]
+mod test_param_deltas {
+    use super::{KorgBlock, KorgParamDeltas, KorgProgramSysEx, KorgSingleParamSysEx, OSC_SPEC, PROGRAM_SPEC};
+    use crate::modulation::{SysExComposer, Updater};
+    use crate::spec::byte_length;
+
+    const BLOCK: [Updater; 4] = [
+        Updater::Const("oscillatorMode", 1),
+        Updater::Sweep("noteMode", -17, 17),
+        Updater::SelectOnZero("osc1"),
+        Updater::Sweep("osc1Register", 64, 200)
+    ];
+
+    fn program(b: i8, osc: i16, c: i16) -> KorgProgramSysEx {
+        let mut sys_ex = KorgProgramSysEx::new();
+        sys_ex.name("2024-01-01");
+        sys_ex.data(1);
+        sys_ex.data(b);
+        sys_ex.data_double_byte(osc);
+        sys_ex.data(c as i8);
+        sys_ex
+    }
+
+    #[test]
+    fn sends_nothing_until_a_whole_program_has_gone() {
+        let mut deltas = KorgParamDeltas::new();
+
+        assert!(deltas.diff(&program(0, 10, 100), &[(KorgBlock::Program, &BLOCK)]).is_none());
+        assert_eq!(deltas.diff(&program(0, 10, 100), &[(KorgBlock::Program, &BLOCK)]).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn sends_only_the_changed_parameters() {
+        let mut deltas = KorgParamDeltas::new();
+        deltas.diff(&program(0, 10, 100), &[(KorgBlock::Program, &BLOCK)]);
+
+        let changes = deltas.diff(&program(-17, 10, 200), &[(KorgBlock::Program, &BLOCK)]).unwrap();
+
+        assert_eq!(changes.iter().map(|c| c.data).collect::<Vec<_>>(), [
+            KorgSingleParamSysEx::new(1, -17).data,
+            KorgSingleParamSysEx::new(4, 200).data
+        ]);
+        assert_eq!(changes[0].data[5..9], [1, 0, 0x6F, 0x7F]);
+        assert_eq!(changes[1].data[5..9], [4, 0, 0x48, 0x01]);
+    }
+
+    #[test]
+    fn sends_a_whole_program_when_an_effect_changes() {
+        let mut deltas = KorgParamDeltas::new();
+        deltas.diff(&program(0, 10, 100), &[(KorgBlock::Effects, &BLOCK)]);
+
+        assert_eq!(deltas.diff(&program(0, 10, 100), &[(KorgBlock::Effects, &BLOCK)]).unwrap().len(), 0);
+        assert!(deltas.diff(&program(1, 10, 100), &[(KorgBlock::Effects, &BLOCK)]).is_none());
+    }
+
+    #[test]
+    fn resyncs_now_and_then() {
+        let mut deltas = KorgParamDeltas::new();
+        deltas.diff(&program(0, 10, 100), &[(KorgBlock::Program, &BLOCK)]);
+        for _ in 0..KorgParamDeltas::RESYNC_EVERY {
+            assert!(deltas.diff(&program(0, 10, 100), &[(KorgBlock::Program, &BLOCK)]).is_some());
+        }
+        assert!(deltas.diff(&program(0, 10, 100), &[(KorgBlock::Program, &BLOCK)]).is_none());
+        assert!(deltas.diff(&program(0, 10, 100), &[(KorgBlock::Program, &BLOCK)]).is_some());
+    }
+
+    #[test]
+    fn numbers_each_parameter_by_its_byte_in_the_built_in_specs() {
+        let numbered = |block: KorgBlock, spec: &[Updater], from: u16| {
+            let mut at = from;
+            for (i, u) in spec.iter().enumerate() {
+                if let Some(p) = block.param_number(u.name()) {
+                    assert_eq!(p, at, "{}", u.name());
+                }
+                at += byte_length(&spec[i..=i]) as u16;
+            }
+            at
+        };
+
+        assert_eq!(numbered(KorgBlock::Program, &PROGRAM_SPEC, 0), 30);
+        assert_eq!(numbered(KorgBlock::Osc(0), &OSC_SPEC, 30), 77);
+        assert_eq!(numbered(KorgBlock::Osc(1), &OSC_SPEC, 77), 124);
+        assert_eq!(KorgBlock::Osc(1).param_number("vdfCutoff"), Some(87));
+        assert_eq!(KorgBlock::Program.param_number("cutoffTypeDetails"), None);
+    }
+}
+
+
+#[cfg(test)]
+mod test_effects {
+    use super::{check_distinct, KorgEffectSelector, PHASER, TREMOLO};
+    use crate::modulation::Selector;
+    use rand::{SeedableRng, rngs::StdRng};
+
+    #[test]
+    fn needs_two_different_effect_numbers() {
+        assert!(check_distinct(&[PHASER, TREMOLO]).is_ok());
+        assert_eq!(check_distinct(&[PHASER, TREMOLO, PHASER]).unwrap_err(), "effect 32 is listed more than once");
+        assert_eq!(check_distinct(&[PHASER]).unwrap_err(), "at least two different effects are needed");
+    }
+
+    #[test]
+    fn always_changes_to_the_other_effect() {
+        let available = [PHASER, TREMOLO];
+        let mut selector = KorgEffectSelector::new(&available, StdRng::seed_from_u64(1));
+        for _ in 0..10 {
+            let before = selector.val(1);
+            selector.next1();
+            assert_ne!(selector.val(1), before);
+        }
+    }
+}
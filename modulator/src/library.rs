@@ -0,0 +1,139 @@
+use crate::d110::{D110SysEx, D110Reply};
+use json::JsonValue;
+use std::path::Path;
+
+
+// the latest data written to each block of D110 memory, so a rig state can be saved and sent again
+pub struct D110Library {
+    blocks: Vec<D110Reply>
+}
+
+impl D110Library {
+    pub fn new() -> D110Library {
+        D110Library {
+            blocks: Vec::new()
+        }
+    }
+
+    pub fn record(&mut self, written: &D110SysEx) {
+        let block = D110Reply::parse(&written.to_send()).unwrap();
+        match self.blocks.iter_mut().find(|b| b.address == block.address) {
+            Some(b) if b.data.len() > block.data.len() => b.data[..block.data.len()].copy_from_slice(&block.data),
+            Some(b) => *b = block,
+            None => self.blocks.push(block)
+        }
+    }
+
+    pub fn messages(&self) -> Vec<D110SysEx> {
+        self.blocks.iter().map(|b| D110SysEx::write(b.address, &b.data)).collect()
+    }
+
+    pub fn to_syx(&self) -> Vec<u8> {
+        self.messages().iter().flat_map(|m| m.to_send()).collect()
+    }
+
+    pub fn from_syx(bytes: &[u8]) -> Result<D110Library, String> {
+        let blocks = bytes.split_inclusive(|b| *b == 0xF7)
+            .enumerate()
+            .map(|(i, msg)| D110Reply::parse(msg).map_err(|e| format!("message {}: {:?}", i + 1, e)))
+            .collect::<Result<Vec<D110Reply>, String>>()?;
+        Ok(D110Library { blocks })
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        JsonValue::Array(self.blocks.iter().map(|b| b.to_json()).collect())
+    }
+
+    pub fn from_json(j: &JsonValue) -> Result<D110Library, String> {
+        if !j.is_array() {
+            return Err(String::from("expected an array of blocks"));
+        }
+        let blocks = j.members().map(D110Reply::from_json).collect::<Result<Vec<D110Reply>, String>>()?;
+        Ok(D110Library { blocks })
+    }
+
+    // .json files are read as the readable form, anything else as sys ex
+    pub fn load(path: &str) -> Result<D110Library, String> {
+        let loaded = if path.ends_with(".json") {
+            let contents = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+            json::parse(&contents).map_err(|e| e.to_string()).and_then(|j| D110Library::from_json(&j))
+        } else {
+            std::fs::read(path).map_err(|e| e.to_string()).and_then(|bytes| D110Library::from_syx(&bytes))
+        };
+        loaded.map_err(|e| format!("{}: {}", path, e))
+    }
+
+    // writes both forms, <stem>.syx and <stem>.json
+    pub fn save(&self, stem: &str) -> Result<(), String> {
+        for (path, contents) in [
+            (format!("{}.syx", stem), self.to_syx()),
+            (format!("{}.json", stem), self.to_json().pretty(2).into_bytes())
+        ] {
+            std::fs::write(Path::new(&path), contents).map_err(|e| format!("{}: {}", path, e))?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test_library {
+    use super::D110Library;
+    use crate::d110::{set_up_tone, D110};
+    use crate::modulation::SysExComposer;
+
+    fn rig() -> D110Library {
+        let mut library = D110Library::new();
+        library.record(&D110.init_system());
+        library.record(&D110.init_part(1));
+        library.record(&set_up_tone(2));
+        library
+    }
+
+    #[test]
+    fn overlays_later_writes_to_the_same_block() {
+        let mut library = rig();
+        let mut tone = set_up_tone(2);
+        tone.data(36);
+        tone.data(55);
+        library.record(&tone);
+        let with_partial = library.to_syx();
+
+        library.record(&set_up_tone(2));
+
+        assert_eq!(library.messages().len(), 3);
+        assert_eq!(library.to_syx(), with_partial);
+    }
+
+    #[test]
+    fn round_trips_through_sys_ex() {
+        let library = rig();
+
+        let loaded = D110Library::from_syx(&library.to_syx()).unwrap();
+
+        assert_eq!(loaded.to_syx(), library.to_syx());
+        assert!(D110Library::from_syx(&library.to_syx()[..20]).is_err());
+    }
+
+    #[test]
+    fn round_trips_through_readable_json() {
+        let library = rig();
+
+        let j = json::parse(&library.to_json().dump()).unwrap();
+        let loaded = D110Library::from_json(&j).unwrap();
+
+        assert_eq!(j[0]["address"], "100001");
+        assert_eq!(j[0]["values"]["reverbMode"], 9);
+        assert_eq!(j[1]["values"]["timbre1_toneNumber"], 63);
+        assert_eq!(j[2]["values"]["tone2_name"], "tone2");
+        assert_eq!(loaded.to_syx(), library.to_syx());
+    }
+
+    #[test]
+    fn rejects_json_with_missing_values() {
+        let mut j = rig().to_json();
+        j[1]["values"].remove("timbre1_pan");
+
+        assert!(D110Library::from_json(&j).is_err());
+    }
+}
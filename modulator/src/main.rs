@@ -5,40 +5,103 @@ mod midi;
 mod d110;
 mod utils;
 mod modulation;
+mod spec;
+mod library;
+mod controller;
+mod yamaha;
+mod scheduler;
+mod notification;
+#[cfg(test)]
+mod simulator;
 
 use crate::modulation::{
+    Updater,
     SysExComposer,
     PairedUpdater,
     StepInterval,
-    SweepState,
     Selector
 };
 use crate::d110::{
-    init_d110,
-    init_timbre,
     set_up_tone,
+    set_up_system,
+    set_up_rhythm,
+    reserve_rhythm_partials,
+    check_partial_reserve,
+    DeviceProfile,
+    D110,
     PARTIAL_SPEC,
-    D110SysEx
+    PARTIAL_LIMITS,
+    PARTIAL_PREFIXES,
+    FIRST_RHYTHM_KEY,
+    RHYTHM_KEYS,
+    RhythmKey,
+    D110SysEx,
+    D110Reply,
+    D110Memory,
+    D110WaveSelector
 };
 use crate::korg::{
+    KorgProgram,
+    KorgProgramDumpRequest,
     KorgProgramSysEx,
+    KorgProgramBank,
     KorgInitSysEx,
     KorgSingleParamSysEx,
+    KorgParamDeltas,
+    KorgBlock,
     PROGRAM_SPEC,
+    PROGRAM_LIMITS,
     OSC_SPEC,
+    OSC_LIMITS,
+    EFFECT_LIMITS,
     KorgOscSelector,
-    KorgEffectSelector
+    KorgEffectSelector,
+    Effect,
+    effects_from
+};
+use crate::spec::{SpecFile, ParamLimits};
+use crate::library::D110Library;
+use crate::notification::PlayNotification;
+use crate::controller::{ControllerDevice, ControllerComposer};
+use crate::scheduler::{
+    ScheduledOut,
+    D110_LINK,
+    KORG_MIDI_LINK,
+    KORG_SERIAL_LINK,
+    FM_LINK,
+    CONTROLLER_LINK
+};
+use crate::yamaha::{
+    YamahaSysEx,
+    FmModel,
+    TX81Z_PITCH_EG_SPEC,
+    TX81Z_ACED_OPERATOR_SPEC,
+    TX81Z_ACED_SPEC
+};
+use crate::midi::{
+    MidiError,
+    MidiMessage,
+    MidiSink,
+    MidiOut,
+    MidiOutDevices,
+    MidiIn,
+    MidiInDevices,
+    PortMidi,
+    SerialOut,
+    RecordingOut
 };
-use crate::midi::{MidiMessage, MidiOut, MidiOutDevices};
 use std::{
     thread,
+    cell::Cell,
+    ops::Range,
     time::{Duration, Instant},
-    sync::{mpsc, mpsc::{Sender, Receiver}},
+    sync::{mpsc, mpsc::{Sender, Receiver, RecvTimeoutError}, Arc, Mutex},
     io::{prelude::*, BufReader},
     net::TcpListener,
-    collections::HashMap
+    path::Path
 };
-use rand::prelude::SliceRandom;
+use rand::{SeedableRng, rngs::StdRng};
+use json::{JsonValue, object};
 
 
 struct DummySelector;
@@ -58,46 +121,71 @@ impl Selector for DummySelector {
 
 
 struct TimeBasedInterval {
-    start: Instant
+    start: Instant,
+    offset_ms: Cell<f32>
 }
 
 impl TimeBasedInterval {
     fn new() -> TimeBasedInterval {
+        TimeBasedInterval::resumed_from(0.0)
+    }
+
+    fn resumed_from(offset_ms: f32) -> TimeBasedInterval {
         TimeBasedInterval {
-            start: Instant::now()
+            start: Instant::now(),
+            offset_ms: Cell::new(offset_ms)
         }
     }
+
+    fn advance(&self, ms: f32) {
+        self.offset_ms.set(self.offset_ms.get() + ms);
+    }
 }
 
 impl StepInterval for TimeBasedInterval {
     fn interval(&self) -> f32 {
-        self.start.elapsed().as_millis() as f32
+        self.offset_ms.get() + self.start.elapsed().as_millis() as f32
     }
 }
 
-struct FixedEquivalentMillisInterval {
-    int: u32
+// the same clock running slower, for sweeps that should take most of a piece to come round
+struct SlowedInterval<'a> {
+    interval: &'a dyn StepInterval,
+    by: f32
 }
 
-impl FixedEquivalentMillisInterval {
-    fn new(int: u32) -> FixedEquivalentMillisInterval {
-        FixedEquivalentMillisInterval {
-            int
-        }
+impl StepInterval for SlowedInterval<'_> {
+    fn interval(&self) -> f32 {
+        self.interval.interval() / self.by
     }
 }
 
-impl StepInterval for FixedEquivalentMillisInterval {
-    fn interval(&self) -> f32 {
-        self.int as f32
+fn report_failure(device: &str, res: Result<(), MidiError>) {
+    if let Err(e) = res {
+        println!("{} send failed: {}", device, e);
     }
 }
 
+
 const NUM_D110_PARTS: usize = 3;
+const D110_SYSTEM_SLOWER_BY: f32 = 10.0;
 
-fn update_d110(updater: &mut PairedUpdater, d110_midi_out: &mut MidiOut) {
-    let mut dummy_1 = DummySelector::new();
-    let mut dummy_2 = DummySelector::new();
+struct D110Specs<'a> {
+    partial: Vec<Updater<'a>>,
+    system: Vec<Updater<'a>>,
+    rhythm: Option<Vec<Updater<'a>>> // for each key, with --d110-rhythm
+}
+
+// only the runs that changed, as rewriting a whole block is audible
+fn send_d110_changes(block: &D110SysEx, d110_midi_out: &mut dyn MidiSink, library: &Mutex<D110Library>, memory: &mut D110Memory) {
+    for change in memory.changes(block) {
+        report_failure("D110", d110_midi_out.send_sys_ex(&change.to_send()));
+    }
+    library.lock().unwrap().record(block);
+}
+
+fn update_d110(updater: &mut PairedUpdater, partial_spec: &[Updater], waves: &mut [D110WaveSelector], parts: Range<usize>, d110_midi_out: &mut dyn MidiSink, library: &Mutex<D110Library>, memory: &mut D110Memory) {
+    let mut dummy = DummySelector::new();
 
     let tones: [& mut D110SysEx; NUM_D110_PARTS] = [
         &mut set_up_tone(1),
@@ -105,104 +193,451 @@ fn update_d110(updater: &mut PairedUpdater, d110_midi_out: &mut MidiOut) {
         &mut set_up_tone(3)
     ];
 
-    let prefixes = ["A_1", "B_3", "C_2", "D_4"];
-    for t in 0..NUM_D110_PARTS {
-        for p in prefixes {
-            updater.update(tones[t], &mut dummy_1, &mut dummy_2, &PARTIAL_SPEC, Some(&*format!("tone{}_partial{}", t + 1, p)));
+    for t in parts.clone() {
+        for p in PARTIAL_PREFIXES {
+            updater.update(tones[t], &mut waves[t], &mut dummy, partial_spec, Some(&*format!("tone{}_partial{}", t + 1, p)));
+        }
+    }
+
+    // a spec that doesn't select the waves leaves the structures as set_up_tone has them
+    if partial_spec.iter().any(|u| matches!(u, Updater::SelectOnZero(_))) {
+        // the tones not written this time still change wave on the alternator, and send it when they next are
+        for t in (0..NUM_D110_PARTS).filter(|t| !parts.contains(t)) {
+            for p in PARTIAL_PREFIXES {
+                let idx = p.chars().last().and_then(|c| c.to_digit(10)).unwrap() as u8;
+                if updater.alternator_reached(idx) {
+                    waves[t].next(idx);
+                }
+            }
+        }
+        for t in parts.clone() {
+            tones[t].set_structures(waves[t].structures());
         }
     }
 
     updater.sweep_alternator();
 
-    for t in 0..NUM_D110_PARTS {
-        let v = tones[t].to_send();
-        d110_midi_out.send_sys_ex(&v);
+    for t in parts {
+        send_d110_changes(tones[t], d110_midi_out, library, memory);
+    }
+}
+
+
+fn verify_d110_write(d110_midi_out: &mut dyn MidiSink, d110_in: &mut MidiIn, written: &D110SysEx) {
+    let expected = D110Reply::parse(&written.to_send()).unwrap();
+    report_failure("D110", d110_midi_out.flush());
+    if let Err(e) = d110_in.clear() {
+        println!("D110 receive failed: {}", e);
+        return;
+    }
+    report_failure("D110", d110_midi_out.send_sys_ex(&D110SysEx::request(expected.address, expected.data.len() as u32).to_send()));
+
+    match d110_in.wait_for_sys_ex(Duration::from_millis(1000)) {
+        Ok(Some(reply)) => match D110Reply::parse(&reply) {
+            Ok(actual) => {
+                let mut matched = true;
+                for ((key, wrote), (_, read)) in expected.values().iter().zip(actual.values()) {
+                    if *wrote != read {
+                        println!("D110 {}: wrote {}, read back {}", key, wrote, read);
+                        matched = false;
+                    }
+                }
+                for t in 1..9 {
+                    if let Some(name) = expected.tone_name(t) {
+                        if actual.tone_name(t).as_ref() != Some(&name) {
+                            println!("D110 tone {} name: wrote {}, read back {:?}", t, name, actual.tone_name(t));
+                            matched = false;
+                        }
+                    }
+                }
+                if matched {
+                    println!("D110 {:06X} verified", expected.address);
+                }
+            },
+            Err(e) => println!("D110 reply rejected: {:?}", e)
+        },
+        Ok(None) => println!("no reply from D110 for {:06X}", expected.address),
+        Err(e) => println!("D110 receive failed: {}", e)
     }
 }
 
 
-fn receive_play_notifications(d110_number: i32) {
-    let mut d110_midi_out = MidiOut::using_device(d110_number);
-    let d110_init = init_d110();
-    d110_midi_out.send_sys_ex(&d110_init.to_send());
-    for t in 1..9 {
-        println!("sending timbre {}", t);
-        d110_midi_out.send_sys_ex(&init_timbre(t).to_send());
+fn save_d110_library(library: &Mutex<D110Library>) {
+    let stem = format!("d110_{}", utils::now());
+    match library.lock().unwrap().save(&stem) {
+        Ok(_) => println!("D110 setup saved to {}.syx and {}.json", stem, stem),
+        Err(e) => println!("could not save D110 setup: {}", e)
     }
-    for t in 1..9 {
-        println!("intitialising part {}", t);
-        d110_midi_out.send_sys_ex(&set_up_tone(t).to_send());
+}
+
+fn update_d110_system(updater: &mut PairedUpdater, system_spec: &[Updater], d110_midi_out: &mut dyn MidiSink, library: &Mutex<D110Library>, memory: &mut D110Memory) {
+    if system_spec.is_empty() {
+        return;
+    }
+    let mut dummy_1 = DummySelector::new();
+    let mut dummy_2 = DummySelector::new();
+    let mut system = set_up_system();
+    updater.update(&mut system, &mut dummy_1, &mut dummy_2, system_spec, Some("system"));
+    send_d110_changes(&system, d110_midi_out, library, memory);
+}
+
+fn update_d110_rhythm(updater: &mut PairedUpdater, rhythm_spec: &[Updater], d110_midi_out: &mut dyn MidiSink, library: &Mutex<D110Library>, memory: &mut D110Memory) {
+    let mut dummy_1 = DummySelector::new();
+    let mut dummy_2 = DummySelector::new();
+    let mut rhythm = set_up_rhythm();
+    for key in FIRST_RHYTHM_KEY..FIRST_RHYTHM_KEY + RHYTHM_KEYS {
+        updater.update(&mut rhythm, &mut dummy_1, &mut dummy_2, rhythm_spec, Some(&*format!("key{}", key)));
+    }
+    send_d110_changes(&rhythm, d110_midi_out, library, memory);
+}
+
+fn set_up_d110(d110_midi_out: &mut dyn MidiSink, d110_in: Option<&mut MidiIn>, setup: Vec<D110SysEx>, library: &Mutex<D110Library>) -> D110Memory {
+    let mut memory = D110Memory::new();
+    for sys_ex in &setup {
+        report_failure("D110", d110_midi_out.send_sys_ex(&sys_ex.to_send()));
+        library.lock().unwrap().record(sys_ex);
+        memory.record(sys_ex);
     }
     println!("D110 init sent");
 
+    if let Some(midi_in) = d110_in {
+        for sys_ex in &setup {
+            verify_d110_write(d110_midi_out, midi_in, sys_ex);
+        }
+    }
+    memory
+}
+
+// one set of sweeps for the session, following the clock, updated on each play notification and
+// with an interval also by the clock between them; the reverb, partial reserves and any rhythm keys
+// follow a slower clock
+fn modulate_d110(d110_midi_out: &mut dyn MidiSink, specs: &D110Specs, mut rng: StdRng, library: &Mutex<D110Library>, mut memory: D110Memory, notifications: &Receiver<PlayNotification>, every: Option<Duration>) {
+    let interval = TimeBasedInterval::new();
+    let mut updater = PairedUpdater::new(&interval, StdRng::from_rng(&mut rng).unwrap());
+    let slowed = SlowedInterval { interval: &interval, by: D110_SYSTEM_SLOWER_BY };
+    let mut system_updater = PairedUpdater::new(&slowed, StdRng::from_rng(&mut rng).unwrap());
+    let mut waves = (1..=NUM_D110_PARTS).map(|t| D110WaveSelector::new(&format!("tone{}", t), StdRng::from_rng(&mut rng).unwrap())).collect::<Vec<_>>();
+
+    loop {
+        let next = match every {
+            Some(every) => notifications.recv_timeout(every).or_else(|e| match e {
+                RecvTimeoutError::Timeout => Ok(PlayNotification::default()),
+                RecvTimeoutError::Disconnected => Err(())
+            }),
+            None => notifications.recv().map_err(|_| ())
+        };
+        let Ok(next) = next else {
+            return;
+        };
+
+        // notes that came in while the last update was being sent are taken together, not one update each
+        let mut step_ms = next.step_ms();
+        let mut part = next.part(NUM_D110_PARTS);
+        while let Ok(more) = notifications.try_recv() {
+            step_ms += more.step_ms();
+            if more.part(NUM_D110_PARTS) != part {
+                part = None;
+            }
+        }
+
+        interval.advance(step_ms);
+        let parts = match part {
+            Some(p) => p..p + 1,
+            None => 0..NUM_D110_PARTS
+        };
+        update_d110(&mut updater, &specs.partial, &mut waves, parts, d110_midi_out, library, &mut memory);
+        update_d110_system(&mut system_updater, &specs.system, d110_midi_out, library, &mut memory);
+        if let Some(rhythm) = &specs.rhythm {
+            update_d110_rhythm(&mut system_updater, rhythm, d110_midi_out, library, &mut memory);
+        }
+    }
+}
+
+fn receive_play_notifications(notify: Sender<PlayNotification>) {
     let listener = TcpListener::bind("0.0.0.0:7878").unwrap();
     println!("tcp listener started on port 7878");
 
-    let mut count: u32 = 0;
     for stream in listener.incoming() {
         let mut stream = stream.unwrap();
-        let buf_reader = BufReader::new(&mut stream);
-        let http_request: Vec<_> = buf_reader
-            .lines()
-            .map(|result| result.unwrap())
-            .take_while(|line| !line.is_empty())
-            .collect();
-
-        count += 1;
+        // a client that stops short of its Content-Length mustn't hold up the notifications after it
+        if let Err(e) = stream.set_read_timeout(Some(Duration::from_secs(2))) {
+            println!("could not time out play notification reads: {}", e);
+        }
+        let notification = PlayNotification::read(&mut BufReader::new(&mut stream));
 
-        let status_line = "HTTP/1.1 200 OK";
+        let status_line = match &notification {
+            Ok(_) => "HTTP/1.1 200 OK",
+            Err(e) => {
+                println!("ignoring play notification: {}", e);
+                "HTTP/1.1 400 Bad Request"
+            }
+        };
         let contents = json::stringify(vec![0]);
         let length = contents.len();
         let response = format!("{status_line}\r\nContent-Type: application/json; charset=UTF-8\r\nContent-Length: {length}\r\n\r\n{contents}");
         stream.write_all(response.as_bytes()).unwrap();
 
-        let interval = FixedEquivalentMillisInterval::new(1000 * count);
-        let mut updater = PairedUpdater::new(&interval);
-        update_d110(&mut updater, &mut d110_midi_out);
+        if let Ok(n) = notification {
+            if notify.send(n).is_err() {
+                return;
+            }
+        }
     }
 }
 
 
-fn modulate_korg<C>(cmd_dump_rx: &Receiver<C>, res_tx: &Sender<HashMap<std::string::String, SweepState>>, first_tx: &Sender<i32>) {
-    let mut port = serialport::new("/dev/ttyUSB0", 38400)
-                    .timeout(Duration::from_millis(1000))
-                    .open()
-                    .expect("Failed to open port");
+fn modulate_controllers(port: &mut dyn MidiSink, device: &ControllerDevice, rng: StdRng) {
+    let (updaters, controllers): (Vec<Updater>, Vec<_>) = exit_if_invalid(device.targets()).into_iter().unzip();
+    let interval = TimeBasedInterval::new();
+    let mut updater = PairedUpdater::new(&interval, rng);
+    let mut composer = ControllerComposer::new(device.channel, controllers);
+    let mut dummy_1 = DummySelector::new();
+    let mut dummy_2 = DummySelector::new();
+
+    loop {
+        composer.start();
+        updater.update(&mut composer, &mut dummy_1, &mut dummy_2, &updaters, None);
+        for m in &composer.messages {
+            report_failure(&device.name, port.send(m));
+        }
+        thread::sleep(Duration::from_millis(device.interval_ms));
+    }
+}
+
+fn controller_output(device: &ControllerDevice, record_dir: Option<&String>) -> Box<dyn MidiSink + Send> {
+    match record_dir {
+        Some(dir) => Box::new(RecordingOut::to_file(&format!("{}/{}.raw", dir, device.name))),
+        None => {
+            let number = MidiOutDevices::index_of(&device.port).unwrap_or_else(|| {
+                println!("no MIDI output named {} for {}", device.port, device.name);
+                std::process::exit(1);
+            });
+            println!("{} port {}", device.name, number);
+            Box::new(ScheduledOut::new(CONTROLLER_LINK, Box::new(MidiOut::using_device(number).expect("Failed to open controller output"))))
+        }
+    }
+}
+
+
+struct FmSpecs<'a> {
+    operator: Vec<Updater<'a>>,
+    voice: Vec<Updater<'a>>
+}
+
+// the TX81Z takes its extra operator parameters in an ACED, which has to arrive before the VCED
+fn update_fm(updater: &mut PairedUpdater, model: FmModel, specs: &FmSpecs, name: &str) -> Vec<YamahaSysEx> {
+    let mut dummy_1 = DummySelector::new();
+    let mut dummy_2 = DummySelector::new();
+    let mut voices = Vec::new();
+
+    let mut voice = match model {
+        FmModel::Dx7 => YamahaSysEx::dx7_voice(),
+        FmModel::Tx81z => {
+            let mut aced = YamahaSysEx::tx81z_aced();
+            for op in model.operators() {
+                updater.update(&mut aced, &mut dummy_1, &mut dummy_2, &TX81Z_ACED_OPERATOR_SPEC, Some(op));
+            }
+            updater.update(&mut aced, &mut dummy_1, &mut dummy_2, &TX81Z_ACED_SPEC, None);
+            voices.push(aced);
+            YamahaSysEx::tx81z_vced()
+        }
+    };
+    for op in model.operators() {
+        updater.update(&mut voice, &mut dummy_1, &mut dummy_2, &specs.operator, Some(op));
+    }
+    updater.update(&mut voice, &mut dummy_1, &mut dummy_2, &specs.voice, None);
+    voice.name(name);
+    if model == FmModel::Tx81z {
+        updater.update(&mut voice, &mut dummy_1, &mut dummy_2, &TX81Z_PITCH_EG_SPEC, None);
+    }
+    voices.push(voice);
+    voices
+}
 
+fn modulate_fm(port: &mut dyn MidiSink, model: FmModel, specs: &FmSpecs, rng: StdRng) {
     let interval = TimeBasedInterval::new();
-    let mut updater = PairedUpdater::new(&interval);
-    let mut effect_selector = KorgEffectSelector::new();
-    let mut osc_selector = KorgOscSelector::new();
+    let mut updater = PairedUpdater::new(&interval, rng);
+    let today = utils::today();
+
+    loop {
+        for voice in update_fm(&mut updater, model, specs, &today) {
+            report_failure("FM", port.send_sys_ex(&voice.to_send()));
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn fm_output(port: &str, record_dir: Option<&String>) -> Box<dyn MidiSink + Send> {
+    match record_dir {
+        Some(dir) => Box::new(RecordingOut::to_file(&format!("{}/fm.syx", dir))),
+        None => {
+            let number = MidiOutDevices::index_of(port).unwrap_or_else(|| {
+                println!("no MIDI output named {} for the FM module", port);
+                std::process::exit(1);
+            });
+            println!("FM port {}", number);
+            Box::new(ScheduledOut::new(FM_LINK, Box::new(MidiOut::using_device(number).expect("Failed to open FM output"))))
+        }
+    }
+}
+
+
+struct KorgSpecs<'a> {
+    program: Vec<Updater<'a>>,
+    osc: Vec<Updater<'a>>,
+    effects: Vec<Effect<'a>>
+}
+
+enum KorgCommand {
+    Snapshot,
+    Export,
+    SaveBank
+}
+
+fn export_korg_program(kpsx: &KorgProgramSysEx, bank: &mut KorgProgramBank) {
+    let filename = format!("korg_program_{}.syx", utils::now());
+    match std::fs::write(&filename, kpsx.data) {
+        Ok(_) => println!("Korg program exported to {}", filename),
+        Err(e) => println!("could not save {}: {}", filename, e)
+    }
+    if bank.is_full() {
+        save_korg_bank(bank, ".");
+        *bank = KorgProgramBank::new();
+    }
+    if let Err(e) = bank.add(kpsx) {
+        println!("could not add to the Korg bank: {}", e);
+    }
+}
+
+fn save_korg_bank(bank: &KorgProgramBank, dir: &str) {
+    match bank.sys_ex(0) {
+        Some(data) => {
+            let path = Path::new(dir).join(format!("korg_bank_{}.syx", utils::now()));
+            match std::fs::write(&path, data) {
+                Ok(_) => println!("{} Korg programs saved to {}", bank.len(), path.display()),
+                Err(e) => println!("could not save {}: {}", path.display(), e)
+            }
+        },
+        None => println!("no Korg programs exported yet")
+    }
+}
+
+// gathers the single program .syx files in dir, in filename order, so earlier sessions' exports can be combined
+fn assemble_korg_bank(dir: &str) -> Result<KorgProgramBank, String> {
+    let mut paths = std::fs::read_dir(dir).map_err(|e| format!("{}: {}", dir, e))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "syx"))
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    let mut bank = KorgProgramBank::new();
+    for path in paths {
+        let dump = std::fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        match KorgProgramSysEx::from_dump(&dump) {
+            Some(_) if bank.is_full() => println!("bank full, leaving out {}", path.display()),
+            Some(program) => bank.add(&program)?,
+            None => println!("{} is not a single Korg program, leaving it out", path.display())
+        }
+    }
+    Ok(bank)
+}
+
+fn restore_korg(snapshot: &JsonValue, updater: &mut PairedUpdater, osc_selector: &mut KorgOscSelector, effect_selector: &mut KorgEffectSelector) -> Result<(), String> {
+    // everything is checked before anything changes, so a bad snapshot leaves a fresh start
+    let selected = |key: &str, idx: usize| snapshot[key][idx].as_u16().ok_or(format!("snapshot has no {} {}", key, idx + 1));
+    let (osc1, osc2) = (selected("osc", 0)?, selected("osc", 1)?);
+    let (eff1, eff2) = (effect_selector.numbered(selected("effects", 0)?)?, effect_selector.numbered(selected("effects", 1)?)?);
+    updater.restore(&snapshot["sweeps"])?;
+    osc_selector.select(osc1, osc2);
+    effect_selector.eff1 = eff1;
+    effect_selector.eff2 = eff2;
+    Ok(())
+}
+
+// one cycle: composes the next program and sends it, or with deltas only the parameters that changed since the last
+fn update_korg(port: &mut dyn MidiSink, updater: &mut PairedUpdater, osc_selector: &mut KorgOscSelector, effect_selector: &mut KorgEffectSelector, specs: &KorgSpecs, name: &str, deltas: Option<&mut KorgParamDeltas>) -> KorgProgramSysEx {
+    let mut kpsx = KorgProgramSysEx::new();
+    kpsx.name(name);
+
+    let eff1_updater = &effect_selector.eff1.updater;
+    let eff2_updater = &effect_selector.eff2.updater;
+    let pre_eff = &effect_selector.pre_eff();
+
+    updater.update(&mut kpsx, osc_selector, effect_selector, &specs.program, None);
+    updater.update(&mut kpsx, osc_selector, effect_selector, &specs.osc, Some("osc1"));
+    updater.update(&mut kpsx, osc_selector, effect_selector, &specs.osc, Some("osc2"));
+    updater.update(&mut kpsx, osc_selector, effect_selector, pre_eff, None);
+    updater.update(&mut kpsx, osc_selector, effect_selector, eff1_updater, Some("eff1"));
+    updater.update(&mut kpsx, osc_selector, effect_selector, eff2_updater, Some("eff2"));
+    updater.sweep_alternator();
+
+    let changes = deltas.and_then(|d| d.diff(&kpsx, &[
+        (KorgBlock::Program, &specs.program[..]),
+        (KorgBlock::Osc(0), &specs.osc[..]),
+        (KorgBlock::Osc(1), &specs.osc[..]),
+        (KorgBlock::Effects, &pre_eff[..]),
+        (KorgBlock::Effects, &eff1_updater[..]),
+        (KorgBlock::Effects, &eff2_updater[..])
+    ]));
+    match changes {
+        Some(changes) => {
+            for change in changes {
+                report_failure("Korg", port.send_sys_ex(&change.data));
+            }
+        },
+        None => report_failure("Korg", port.send_sys_ex(&kpsx.data))
+    }
+    kpsx
+}
+
+// with deltas only the parameters that changed are sent after the first program, leaving room for a shorter update interval
+struct KorgOptions {
+    resume: Option<JsonValue>,
+    deltas: bool,
+    update_interval: Duration
+}
+
+fn modulate_korg(port: &mut dyn MidiSink, specs: &KorgSpecs, mut rng: StdRng, options: KorgOptions, cmd_rx: &Receiver<KorgCommand>, res_tx: &Sender<JsonValue>, first_tx: &Sender<i32>) {
+    let interval = match &options.resume {
+        Some(snapshot) => TimeBasedInterval::resumed_from(snapshot["interval_ms"].as_f32().unwrap_or(0.0)),
+        None => TimeBasedInterval::new()
+    };
+    let mut updater = PairedUpdater::new(&interval, StdRng::from_rng(&mut rng).unwrap());
+    let mut effect_selector = KorgEffectSelector::new(&specs.effects, StdRng::from_rng(&mut rng).unwrap());
+    let mut osc_selector = KorgOscSelector::new(StdRng::from_rng(&mut rng).unwrap());
+
+    if let Some(snapshot) = &options.resume {
+        match restore_korg(snapshot, &mut updater, &mut osc_selector, &mut effect_selector) {
+            Ok(_) => println!("Korg modulation resumed"),
+            Err(e) => println!("could not resume from snapshot, starting afresh: {}", e)
+        }
+    }
 
     let today = utils::today();
     let mut first = true;
+    let mut bank = KorgProgramBank::new();
+    let mut deltas = KorgParamDeltas::new();
 
     loop {
-        let mut kpsx = KorgProgramSysEx::new();
-        kpsx.name(&today);
-
-        let eff1_updater = &effect_selector.eff1.updater;
-        let eff2_updater = &effect_selector.eff2.updater;
-        let pre_eff = &effect_selector.pre_eff();
-
-        updater.update(&mut kpsx, &mut osc_selector, &mut effect_selector, &PROGRAM_SPEC, None);
-        updater.update(&mut kpsx, &mut osc_selector, &mut effect_selector, &OSC_SPEC, Some("osc1"));
-        updater.update(&mut kpsx, &mut osc_selector, &mut effect_selector, &OSC_SPEC, Some("osc2"));
-        updater.update(&mut kpsx, &mut osc_selector, &mut effect_selector, pre_eff, None);
-        updater.update(&mut kpsx, &mut osc_selector, &mut effect_selector, eff1_updater, Some("eff1"));
-        updater.update(&mut kpsx, &mut osc_selector, &mut effect_selector, eff2_updater, Some("eff2"));
-        updater.sweep_alternator();
-
-        port.write(&kpsx.data).expect("Write failed!");
-        thread::sleep(Duration::from_millis(100));
+        let kpsx = update_korg(port, &mut updater, &mut osc_selector, &mut effect_selector, specs, &today, options.deltas.then_some(&mut deltas));
+        thread::sleep(options.update_interval);
 
-        match cmd_dump_rx.try_recv() {
-            Ok(_) => {
-                res_tx.send(updater.sweep_state.clone()).unwrap();
+        match cmd_rx.try_recv() {
+            Ok(KorgCommand::Snapshot) => {
+                res_tx.send(object!{
+                    interval_ms: interval.interval(),
+                    sweeps: updater.snapshot(),
+                    osc: [osc_selector.val(1), osc_selector.val(2)],
+                    effects: [effect_selector.val(1), effect_selector.val(2)]
+                }).unwrap();
             },
+            Ok(KorgCommand::Export) => export_korg_program(&kpsx, &mut bank),
+            Ok(KorgCommand::SaveBank) => save_korg_bank(&bank, "."),
             _ => {}
         }
         if first {
+            // the program has to be on the Korg before main selects program mode on the other link
+            report_failure("Korg", port.flush());
             first_tx.send(0).unwrap();
             first = false;
             thread::sleep(Duration::from_millis(100));
@@ -211,33 +646,317 @@ fn modulate_korg<C>(cmd_dump_rx: &Receiver<C>, res_tx: &Sender<HashMap<std::stri
 }
 
 
-fn main() {
+fn capture_korg_program(midi_out: &mut dyn MidiSink, midi_in: &mut MidiIn, effects: &[Effect]) {
+    if let Err(e) = midi_in.clear() {
+        println!("Korg receive failed: {}", e);
+        return;
+    }
+    report_failure("Korg", midi_out.send_sys_ex(&KorgProgramDumpRequest::new().data));
+    match midi_in.wait_for_sys_ex(Duration::from_millis(2000)) {
+        Ok(Some(dump)) => match KorgProgram::from_dump(&dump, effects) {
+            Ok(program) => {
+                for (key, val) in &program.values {
+                    println!("{}: {}", key, val);
+                }
+                let filename = format!("korg_program_{}.json", utils::now());
+                match std::fs::write(&filename, program.to_json().pretty(2)) {
+                    Ok(_) => println!("Korg program saved to {}", filename),
+                    Err(e) => println!("could not save {}: {}", filename, e)
+                }
+            },
+            Err(e) => println!("Korg program not captured: {}", e)
+        },
+        Ok(None) => println!("no program dump received from Korg"),
+        Err(e) => println!("Korg receive failed: {}", e)
+    }
+}
+
+
+// getch gives no line editing, so this echoes the name as it is typed; None if left empty
+fn read_line(g: &getch::Getch) -> Option<String> {
+    let mut line = String::new();
+    loop {
+        match g.getch() {
+            Ok(b'\r') | Ok(b'\n') | Err(_) => break,
+            Ok(127) | Ok(8) => {
+                line.pop();
+            },
+            Ok(c) => {
+                print!("{}", c as char);
+                line.push(c as char);
+            }
+        }
+        std::io::stdout().flush().unwrap_or(());
+    }
+    println!();
+    if line.is_empty() { None } else { Some(line) }
+}
+
+fn input_named(name: &str) -> Option<MidiIn> {
+    match MidiInDevices::index_of(name).map(MidiIn::using_device) {
+        Some(Ok(midi_in)) => Some(midi_in),
+        Some(Err(e)) => {
+            println!("{} input unavailable: {}", name, e);
+            None
+        },
+        None => None
+    }
+}
+
+
+type Outputs = (Box<dyn MidiSink + Send>, Box<dyn MidiSink + Send>, Box<dyn MidiSink + Send>);
+
+fn hardware_outputs() -> Outputs {
     let d110_number = MidiOutDevices::index_of("4i4o MIDI 4").unwrap();
     let korg_number = MidiOutDevices::index_of("4i4o MIDI 3").unwrap();
     println!("D110 port {}", d110_number);
     println!("Korg port {}", korg_number);
 
-    thread::spawn(move || { receive_play_notifications(d110_number); });
-
-    let mut midi_out = MidiOut::using_device(korg_number);
-    midi_out.send_sys_ex(&KorgInitSysEx::new(0x02).data); // select prog
-    midi_out.send(&MidiMessage::program(33, korg::CHANNEL)); // select 33
-    thread::sleep(Duration::from_millis(100));
-
-    midi_out.send_sys_ex(&KorgInitSysEx::new(0x03).data); // edit prog
-    midi_out.send_sys_ex(&KorgSingleParamSysEx::new(0, 1).data); // oscillator mode: Double, on UI, otherwise the screen value overrides th sysEx
-
     let ports = serialport::available_ports().expect("No ports found!");
     for p in ports {
         println!("{} available", p.port_name);
     }
 
-    let (cmd_dump_tx, cmd_dump_rx) = mpsc::channel();
+    (
+        Box::new(ScheduledOut::new(D110_LINK, Box::new(MidiOut::using_device(d110_number).expect("Failed to open D110 output")))),
+        Box::new(ScheduledOut::new(KORG_MIDI_LINK, Box::new(MidiOut::using_device(korg_number).expect("Failed to open Korg output")))),
+        Box::new(ScheduledOut::new(KORG_SERIAL_LINK, Box::new(SerialOut::using_port("/dev/ttyUSB0", KORG_SERIAL_LINK.baud).expect("Failed to open port"))))
+    )
+}
+
+fn recording_outputs(dir: &str) -> Outputs {
+    (
+        Box::new(RecordingOut::to_file(&format!("{}/d110.syx", dir))),
+        Box::new(RecordingOut::to_file(&format!("{}/korg.syx", dir))),
+        Box::new(RecordingOut::to_file(&format!("{}/korg_serial.syx", dir)))
+    )
+}
+
+
+// values the device doesn't accept stop the modulator at startup, unless it was asked to clamp them;
+// parameters it doesn't have always do
+fn check_limits(path: &str, spec: &[Updater], limits: &ParamLimits, clamp: bool) -> Result<(), String> {
+    let report = limits.check(spec);
+    if report.is_empty() {
+        return Ok(());
+    }
+    let report = report.iter().map(|line| format!("{}: {}", path, line)).collect::<Vec<String>>().join("\n");
+    if !limits.unknown(spec).is_empty() {
+        return Err(report);
+    }
+    if !clamp {
+        return Err(format!("{}\n(--clamp-specs pulls them into range)", report));
+    }
+    println!("{}\nclamping to the {} limits", report, limits.device);
+    Ok(())
+}
+
+// with --d110-rhythm the drum map keeps some partials for itself
+fn d110_system_spec<'a>(spec_file: &'a SpecFile, profile: &DeviceProfile, rhythm: bool) -> Result<Vec<Updater<'a>>, String> {
+    let mut spec = spec_file.updaters(&profile.system_spec())?;
+    if rhythm {
+        reserve_rhythm_partials(&mut spec);
+    }
+    Ok(spec)
+}
+
+fn clamped<'a>(mut spec: Vec<Updater<'a>>, limits: &ParamLimits) -> Vec<Updater<'a>> {
+    limits.clamp(&mut spec);
+    spec
+}
+
+fn exit_if_invalid<T>(res: Result<T, String>) -> T {
+    res.unwrap_or_else(|e| {
+        println!("invalid spec {}", e);
+        std::process::exit(1);
+    })
+}
+
+fn arg_value<'a>(args: &'a [String], name: &str) -> Option<&'a String> {
+    args.iter().position(|a| a == name).and_then(|i| args.get(i + 1))
+}
+
+fn arg_values<'a>(args: &'a [String], name: &str) -> Vec<&'a String> {
+    args.windows(2).filter(|w| w[0] == name).map(|w| &w[1]).collect()
+}
+
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(dir) = arg_value(&args, "--bank") {
+        save_korg_bank(&exit_if_invalid(assemble_korg_bank(dir)), dir);
+        return;
+    }
+    let record_dir = arg_value(&args, "--record");
+    // declared first so it outlives every stream main opens
+    let _portmidi = record_dir.is_none().then(PortMidi::initialize);
+
+    let spec_dir = arg_value(&args, "--specs").map(|d| d.as_str());
+    let partial_spec = exit_if_invalid(SpecFile::load(spec_dir, "partial.json"));
+    let system_spec = exit_if_invalid(SpecFile::load(spec_dir, "system.json"));
+    let rhythm_spec = exit_if_invalid(SpecFile::load(spec_dir, "rhythm.json"));
+    let program_spec = exit_if_invalid(SpecFile::load(spec_dir, "program.json"));
+    let osc_spec = exit_if_invalid(SpecFile::load(spec_dir, "osc.json"));
+    // shared with the Korg thread, so a captured program is read with the effects being modulated
+    let effects_spec = Arc::new(exit_if_invalid(SpecFile::load(spec_dir, "effects.json")));
+    let clamp = args.iter().any(|a| a == "--clamp-specs");
+    exit_if_invalid(partial_spec.updaters(&PARTIAL_SPEC).and_then(|spec| check_limits(&partial_spec.path, &spec, &PARTIAL_LIMITS, clamp)));
+    exit_if_invalid(program_spec.updaters(&PROGRAM_SPEC).and_then(|spec| check_limits(&program_spec.path, &spec, &PROGRAM_LIMITS, clamp)));
+    exit_if_invalid(osc_spec.updaters(&OSC_SPEC).and_then(|spec| check_limits(&osc_spec.path, &spec, &OSC_LIMITS, clamp)));
+    exit_if_invalid(effects_from(&effects_spec).and_then(|effects| {
+        effects.iter().try_for_each(|e| check_limits(&effects_spec.path, &e.updater, &EFFECT_LIMITS, clamp))
+    }));
+
+    let fm_model = arg_value(&args, "--fm").map(|name| FmModel::named(name).unwrap_or_else(|| {
+        println!("unknown FM module {}, expected DX7 or TX81Z", name);
+        std::process::exit(1);
+    }));
+    let fm_operator_spec = exit_if_invalid(SpecFile::load(spec_dir, "fm_operator.json"));
+    let fm_voice_spec = exit_if_invalid(SpecFile::load(spec_dir, "fm_voice.json"));
+    if let Some(model) = fm_model {
+        exit_if_invalid(fm_operator_spec.updaters(model.operator_spec()).and_then(|spec| check_limits(&fm_operator_spec.path, &spec, model.operator_limits(), clamp)));
+        exit_if_invalid(fm_voice_spec.updaters(model.voice_spec()).and_then(|spec| check_limits(&fm_voice_spec.path, &spec, model.voice_limits(), clamp)));
+    }
+
+    let seed = match arg_value(&args, "--seed") {
+        Some(s) => s.parse::<u64>().unwrap_or_else(|_| {
+            println!("invalid seed {}", s);
+            std::process::exit(1);
+        }),
+        None => rand::random::<u64>()
+    };
+    println!("session seed {}", seed);
+    let mut session_rng = StdRng::seed_from_u64(seed);
+    let d110_rng = StdRng::from_rng(&mut session_rng).unwrap();
+    let korg_rng = StdRng::from_rng(&mut session_rng).unwrap();
+
+    if let Some(model) = fm_model {
+        let mut port = fm_output(arg_value(&args, "--fm-port").map_or("MIDI 2", |p| p.as_str()), record_dir);
+        let rng = StdRng::from_rng(&mut session_rng).unwrap();
+        thread::spawn(move || {
+            let specs = FmSpecs {
+                operator: clamped(exit_if_invalid(fm_operator_spec.updaters(model.operator_spec())), model.operator_limits()),
+                voice: clamped(exit_if_invalid(fm_voice_spec.updaters(model.voice_spec())), model.voice_limits())
+            };
+            modulate_fm(&mut *port, model, &specs, rng);
+        });
+    }
+
+    for path in arg_values(&args, "--controllers") {
+        let device = exit_if_invalid(ControllerDevice::load(path));
+        let mut port = controller_output(&device, record_dir);
+        let rng = StdRng::from_rng(&mut session_rng).unwrap();
+        thread::spawn(move || modulate_controllers(&mut *port, &device, rng));
+    }
+
+    let resume = arg_value(&args, "--resume").map(|path| {
+        std::fs::read_to_string(path).map_err(|e| e.to_string())
+            .and_then(|contents| json::parse(&contents).map_err(|e| e.to_string()))
+            .unwrap_or_else(|e| {
+                println!("could not read snapshot {}: {}", path, e);
+                std::process::exit(1);
+            })
+    });
+
+    let korg_deltas = args.iter().any(|a| a == "--korg-deltas");
+    let korg_options = KorgOptions {
+        resume,
+        deltas: korg_deltas,
+        update_interval: match arg_value(&args, "--korg-interval") {
+            Some(ms) => Duration::from_millis(ms.parse::<u64>().unwrap_or_else(|_| {
+                println!("invalid Korg update interval {}", ms);
+                std::process::exit(1);
+            })),
+            None => Duration::from_millis(100)
+        }
+    };
+
+    let export_every = arg_value(&args, "--export-every").map(|s| match s.parse::<u64>() {
+        Ok(secs) if secs > 0 => Duration::from_secs(secs),
+        _ => {
+            println!("invalid export interval {}", s);
+            std::process::exit(1);
+        }
+    });
+
+    let profile = match arg_value(&args, "--device") {
+        Some(name) => DeviceProfile::named(name).unwrap_or_else(|| {
+            println!("unknown device {}, expected D-110, MT-32 or CM-32L", name);
+            std::process::exit(1);
+        }),
+        None => &D110
+    };
+    println!("modulating {} partials", profile.name);
+    let d110_rhythm = args.iter().any(|a| a == "--d110-rhythm");
+    exit_if_invalid(d110_system_spec(&system_spec, profile, d110_rhythm).and_then(|spec| {
+        check_partial_reserve(&spec).map_err(|e| format!("{}: {}", system_spec.path, e))?;
+        check_limits(&system_spec.path, &spec, &profile.system_limits, clamp)
+    }));
+    exit_if_invalid(rhythm_spec.updaters(&profile.rhythm_spec()).and_then(|spec| check_limits(&rhythm_spec.path, &spec, &profile.rhythm_limits, clamp)));
+    let d110_setup = match arg_value(&args, "--d110") {
+        Some(path) => exit_if_invalid(D110Library::load(path)).messages(),
+        None if d110_rhythm => profile.setup().into_iter().chain([profile.set_up_rhythm_keys(&RhythmKey::defaults())]).collect(),
+        None => profile.setup()
+    };
+    let d110_library = Arc::new(Mutex::new(D110Library::new()));
+    let d110_library_to_save = Arc::clone(&d110_library);
+
+    let (mut d110_midi_out, mut midi_out, mut korg_port) = match record_dir {
+        Some(dir) => recording_outputs(dir),
+        None => hardware_outputs()
+    };
+
+    let (mut korg_in, mut d110_in) = match record_dir {
+        Some(_) => (None, None),
+        None => (input_named("4i4o MIDI 3"), input_named("4i4o MIDI 4"))
+    };
+
+    let d110_every = arg_value(&args, "--d110-interval").map(|s| match s.parse::<u64>() {
+        Ok(ms) if ms > 0 => Duration::from_millis(ms),
+        _ => {
+            println!("invalid D110 update interval {}", s);
+            std::process::exit(1);
+        }
+    });
+    let (notify_tx, notify_rx) = mpsc::channel();
+    thread::spawn(move || receive_play_notifications(notify_tx));
+    thread::spawn(move || {
+        let specs = D110Specs {
+            partial: clamped(exit_if_invalid(partial_spec.updaters(&PARTIAL_SPEC)), &PARTIAL_LIMITS),
+            system: clamped(exit_if_invalid(d110_system_spec(&system_spec, profile, d110_rhythm)), &profile.system_limits),
+            rhythm: d110_rhythm.then(|| clamped(exit_if_invalid(rhythm_spec.updaters(&profile.rhythm_spec())), &profile.rhythm_limits))
+        };
+        // the input stays open for the session, as the outputs do
+        let memory = set_up_d110(&mut *d110_midi_out, d110_in.as_mut(), d110_setup, &d110_library);
+        modulate_d110(&mut *d110_midi_out, &specs, d110_rng, &d110_library, memory, &notify_rx, d110_every);
+    });
+
+    report_failure("Korg", midi_out.send_sys_ex(&KorgInitSysEx::new(0x02).data)); // select prog
+    report_failure("Korg", midi_out.send(&MidiMessage::program(33, korg::CHANNEL))); // select 33
+    thread::sleep(Duration::from_millis(100));
+
+    report_failure("Korg", midi_out.send_sys_ex(&KorgInitSysEx::new(0x03).data)); // edit prog
+    report_failure("Korg", midi_out.send_sys_ex(&KorgSingleParamSysEx::new(0, 1).data)); // oscillator mode: Double, on UI, otherwise the screen value overrides th sysEx
+
+    let (cmd_korg_tx, cmd_korg_rx) = mpsc::channel();
+    let cmd_export_tx = cmd_korg_tx.clone();
     let (cmd_stop_tx, cmd_stop_rx) = mpsc::channel();
+    let (cmd_capture_tx, cmd_capture_rx) = mpsc::channel();
     let (res_tx, res_rx) = mpsc::channel();
     let (first_tx, first_korg_rx) = mpsc::channel();
 
-    thread::spawn(move || { modulate_korg(&cmd_dump_rx, &res_tx, &first_tx); });
+    let korg_effects_spec = Arc::clone(&effects_spec);
+    thread::spawn(move || {
+        let specs = KorgSpecs {
+            program: clamped(exit_if_invalid(program_spec.updaters(&PROGRAM_SPEC)), &PROGRAM_LIMITS),
+            osc: clamped(exit_if_invalid(osc_spec.updaters(&OSC_SPEC)), &OSC_LIMITS),
+            effects: exit_if_invalid(effects_from(&korg_effects_spec)).into_iter().map(|mut e| {
+                EFFECT_LIMITS.clamp(&mut e.updater);
+                e
+            }).collect()
+        };
+        modulate_korg(&mut *korg_port, &specs, korg_rng, korg_options, &cmd_korg_rx, &res_tx, &first_tx);
+    });
 
     thread::spawn(move || {
         let g = getch::Getch::new();
@@ -245,14 +964,35 @@ fn main() {
             let c: u8 = g.getch().unwrap();
             match c as char {
                 'l' => {
-                    cmd_dump_tx.send(()).unwrap();
-                    for res in &res_rx {
-                        for (key, val) in &res {
-                            println!("{}: {}", key, val.val);
+                    cmd_korg_tx.send(KorgCommand::Snapshot).unwrap();
+                    if let Ok(res) = res_rx.recv() {
+                        for (key, val) in res["sweeps"].entries() {
+                            println!("{}: {}", key, val["val"]);
                         }
-                        break;
                     }
                 },
+                's' => {
+                    cmd_korg_tx.send(KorgCommand::Snapshot).unwrap();
+                    if let Ok(res) = res_rx.recv() {
+                        println!("snapshot name (enter for {}):", utils::now());
+                        let name = read_line(&g).unwrap_or_else(utils::now);
+                        let path = format!("{}.json", name);
+                        match std::fs::write(&path, res.pretty(2)) {
+                            Ok(_) => println!("saved {}", path),
+                            Err(e) => println!("could not save {}: {}", path, e)
+                        }
+                    }
+                },
+                'p' => {
+                    cmd_capture_tx.send(()).unwrap();
+                },
+                'x' => {
+                    cmd_korg_tx.send(KorgCommand::Export).unwrap();
+                },
+                'b' => {
+                    cmd_korg_tx.send(KorgCommand::SaveBank).unwrap();
+                },
+                'd' => save_d110_library(&d110_library_to_save),
                 'q' => {
                     cmd_stop_tx.send(()).unwrap();
                     break;
@@ -262,6 +1002,8 @@ fn main() {
         }
     });
 
+    let capture_effects = exit_if_invalid(effects_from(&effects_spec));
+    let mut last_export = Instant::now();
     loop {
         match cmd_stop_rx.try_recv() {
             Ok(_) => {
@@ -271,12 +1013,58 @@ fn main() {
             _ => thread::sleep(Duration::from_millis(50))
         }
         match first_korg_rx.try_recv() {
+            // single parameter changes only reach the edit buffer, so with deltas the Korg stays in edit mode
+            Ok(0) if korg_deltas => println!("first korg modulation sent, staying in edit mode for parameter changes"),
             Ok(0) => {
-                midi_out.send_sys_ex(&KorgInitSysEx::new(0x02).data); // select prog (deselect edit, otherwise the oscillators don't change
+                report_failure("Korg", midi_out.send_sys_ex(&KorgInitSysEx::new(0x02).data)); // select prog (deselect edit, otherwise the oscillators don't change
                 println!("first korg modulation sent");
             },
             _ => thread::sleep(Duration::from_millis(50))
         }
+        if export_every.is_some_and(|every| last_export.elapsed() >= every) {
+            cmd_export_tx.send(KorgCommand::Export).unwrap();
+            last_export = Instant::now();
+        }
+        if cmd_capture_rx.try_recv().is_ok() {
+            match &mut korg_in {
+                Some(midi_in) => capture_korg_program(&mut *midi_out, midi_in, &capture_effects),
+                None => println!("no Korg input to capture the program from")
+            }
+        }
     }
     thread::sleep(Duration::from_millis(2000));
 }
+
+
+#[cfg(test)]
+mod test_startup {
+    use super::{check_limits, d110_system_spec};
+    use crate::d110::{DEVICE_PROFILES, MT32, SYSTEM_SPEC};
+    use crate::modulation::Updater;
+    use crate::spec::{updaters_from, SpecFile};
+
+    #[test]
+    fn built_in_d110_specs_start_on_every_device_without_clamping() {
+        let built_in = SpecFile::load(None, "system.json").unwrap();
+        assert!(check_limits("system.json", &d110_system_spec(&built_in, &MT32, false).unwrap(), &MT32.system_limits, false).is_ok());
+        for profile in DEVICE_PROFILES {
+            for rhythm in [false, true] {
+                assert!(check_limits("system.json", &d110_system_spec(&built_in, profile, rhythm).unwrap(), &profile.system_limits, false).is_ok());
+            }
+            assert!(check_limits("rhythm.json", &profile.rhythm_spec(), &profile.rhythm_limits, false).is_ok());
+        }
+    }
+
+    #[test]
+    fn a_system_file_written_for_the_d110_stops_the_mt32() {
+        let file = json::parse(&format!("[{}]", SYSTEM_SPEC.iter().map(|u| match u {
+            Updater::Sweep(n, min, max) => format!(r#"{{"type": "sweep", "name": "{}", "min": {}, "max": {}}}"#, n, min, max),
+            Updater::Const(n, v) => format!(r#"{{"type": "const", "name": "{}", "value": {}}}"#, n, v),
+            _ => unreachable!()
+        }).collect::<Vec<String>>().join(", "))).unwrap();
+        let spec = updaters_from(&file, &MT32.system_spec()).unwrap();
+
+        assert_eq!(check_limits("specs/system.json", &spec, &MT32.system_limits, false).unwrap_err(),
+            "specs/system.json: reverbMode sweeps 1 to 8 but the MT-32 takes 0 to 3\n(--clamp-specs pulls them into range)");
+    }
+}
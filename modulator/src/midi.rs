@@ -3,7 +3,11 @@ use std::{
     ptr,
     thread,
     time::Duration,
-    ffi::{CStr}
+    ffi::{CStr},
+    collections::VecDeque,
+    fmt,
+    fs::File,
+    io::{self, Write}
 };
 
 #[repr(C)]
@@ -16,8 +20,15 @@ pub struct PmDeviceInfo {
     pub opened: c_int,
 }
 
-#[allow(dead_code)]
 #[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PmEvent {
+    pub message: u32,
+    pub timestamp: u32
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PmError {
     PmNoError = 0,
     PmGotData = 1,
@@ -32,6 +43,24 @@ pub enum PmError {
     PmBufferMaxSize = -9992,
 }
 
+impl PmError {
+    fn from(code: c_int) -> PmError {
+        match code {
+            0 => PmError::PmNoError,
+            1 => PmError::PmGotData,
+            -10000 => PmError::PmHostError,
+            -9999 => PmError::PmInvalidDeviceId,
+            -9998 => PmError::PmInsufficientMemory,
+            -9997 => PmError::PmBufferTooSmall,
+            -9996 => PmError::PmBufferOverflow,
+            -9995 => PmError::PmBadPtr,
+            -9994 => PmError::PmBadData,
+            -9992 => PmError::PmBufferMaxSize,
+            _ => PmError::PmInternalError
+        }
+    }
+}
+
 #[allow(dead_code)]
 #[link(name = "portmidi")]
 extern "C" {
@@ -39,12 +68,59 @@ extern "C" {
     pub fn Pm_Terminate() -> c_int;
     pub fn Pm_CountDevices() -> c_int;
     pub fn Pm_GetDeviceInfo(id: c_int) -> *const PmDeviceInfo;
-    pub fn Pm_OpenOutput(stream: *const *const c_void, outputDeviceId: c_int, inputDriverInfo: *const c_void, bufferSize: i32, time_proc: *const c_void, time_info: *const c_void, latency: i32) -> PmError;
-    pub fn Pm_WriteShort(stream: *const c_void, timestamp: u32, message: c_uint) -> PmError;
-    pub fn Pm_Close(stream: *const c_void) -> PmError;
-    pub fn Pm_WriteSysEx(stream: *const c_void, when: u32, msg: *const c_uchar) -> PmError;
+    pub fn Pm_OpenOutput(stream: *mut *const c_void, outputDeviceId: c_int, inputDriverInfo: *const c_void, bufferSize: i32, time_proc: *const c_void, time_info: *const c_void, latency: i32) -> c_int;
+    pub fn Pm_WriteShort(stream: *const c_void, timestamp: u32, message: c_uint) -> c_int;
+    pub fn Pm_Close(stream: *const c_void) -> c_int;
+    pub fn Pm_WriteSysEx(stream: *const c_void, when: u32, msg: *const c_uchar) -> c_int;
+    pub fn Pm_OpenInput(stream: *mut *const c_void, inputDeviceId: c_int, inputDriverInfo: *const c_void, bufferSize: i32, time_proc: *const c_void, time_info: *const c_void) -> c_int;
+    pub fn Pm_Poll(stream: *const c_void) -> c_int;
+    pub fn Pm_Read(stream: *const c_void, buffer: *mut PmEvent, length: i32) -> c_int;
+    pub fn Pm_GetErrorText(errnum: c_int) -> *const c_char;
+    pub fn Pm_GetHostErrorText(msg: *mut c_char, len: c_uint);
+}
+
+
+#[derive(Debug)]
+pub enum MidiError {
+    PortMidi(PmError, String),
+    Io(io::Error),
+    Closed
+}
+
+impl fmt::Display for MidiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MidiError::PortMidi(e, text) => write!(f, "{:?}: {}", e, text),
+            MidiError::Io(e) => write!(f, "{}", e),
+            MidiError::Closed => write!(f, "output closed")
+        }
+    }
+}
+
+impl std::error::Error for MidiError {}
+
+impl From<io::Error> for MidiError {
+    fn from(e: io::Error) -> MidiError {
+        MidiError::Io(e)
+    }
+}
+
+fn host_error_text() -> String {
+    const HOST_ERROR_MSG_LEN: usize = 256;
+    let mut msg = [0 as c_char; HOST_ERROR_MSG_LEN];
+    unsafe { Pm_GetHostErrorText(msg.as_mut_ptr(), HOST_ERROR_MSG_LEN as c_uint) };
+    to_string(msg.as_ptr())
+}
+
+fn checked(code: c_int) -> Result<(), MidiError> {
+    match PmError::from(code) {
+        PmError::PmNoError | PmError::PmGotData => Ok(()),
+        PmError::PmHostError => Err(MidiError::PortMidi(PmError::PmHostError, host_error_text())),
+        e => Err(MidiError::PortMidi(e, to_string(unsafe { Pm_GetErrorText(code) })))
+    }
 }
 
+#[derive(Debug, PartialEq, Clone)]
 pub struct MidiMessage {
     pub status: u8,
     pub data1: u8,
@@ -56,6 +132,28 @@ impl MidiMessage {
     pub fn program(p: u8, channel: u8) -> MidiMessage {
         MidiMessage { status: 0xC0 | channel, data1: p, data2: 0, data3: 0 }
     }
+    pub fn control_change(c: u8, v: u8, channel: u8) -> MidiMessage {
+        MidiMessage { status: 0xB0 | channel, data1: c, data2: v, data3: 0 }
+    }
+    pub fn from_u32(m: u32) -> MidiMessage {
+        MidiMessage {
+            status: (m & 0xFF) as u8,
+            data1: ((m >> 8) & 0xFF) as u8,
+            data2: ((m >> 16) & 0xFF) as u8,
+            data3: 0
+        }
+    }
+    pub fn byte_count(&self) -> usize {
+        match self.status {
+            0xF1 | 0xF3 => 2,
+            0xF6..=0xFF => 1,
+            s if (s & 0xE0) == 0xC0 => 2, // program change, channel pressure
+            _ => 3
+        }
+    }
+    pub fn as_bytes(&self) -> Vec<u8> {
+        [self.status, self.data1, self.data2][..self.byte_count()].to_vec()
+    }
     pub fn as_u32(&self) -> u32 {
         (self.data3 as u32) << 24
             | (self.data2 as u32) << 16
@@ -68,56 +166,357 @@ fn to_string(s: *const c_char) -> String {
     unsafe { CStr::from_ptr(s) }.to_str().ok().unwrap().to_owned()
 }
 
+fn index_of_device(substr: &str, input: bool) -> Option<i32> {
+    let n = unsafe { Pm_CountDevices() };
+    for d in 0..n {
+        let info_ptr = unsafe { Pm_GetDeviceInfo(d) };
+        let available = if input { unsafe { (*info_ptr).input } } else { unsafe { (*info_ptr).output } };
+        if 1 == available {
+            let name = to_string(unsafe { (*info_ptr).name }).to_lowercase();
+            if name.contains(&substr.to_lowercase()) {
+                return Some(d);
+            }
+        }
+    }
+    None
+}
+
+// PortMidi is set up once for the whole process, as terminating it closes every stream
+pub struct PortMidi;
+
+impl PortMidi {
+    pub fn initialize() -> PortMidi {
+        unsafe { Pm_Initialize() };
+        PortMidi
+    }
+}
+
+impl Drop for PortMidi {
+    fn drop(&mut self) {
+        unsafe { Pm_Terminate() };
+    }
+}
+
 pub struct MidiOutDevices;
 impl MidiOutDevices {
     pub fn index_of(substr: &str) -> Option<i32> {
-        let n = unsafe { Pm_CountDevices() };
-        for d in 0..n {
-            let info_ptr = unsafe { Pm_GetDeviceInfo(d) };
-            if 1 == unsafe { (*info_ptr).output } {
-                let name = to_string(unsafe { (*info_ptr).name }).to_lowercase();
-                if name.contains(&substr.to_lowercase()) {
-                    return Some(d);
-                }
-            }
-        }
-        None
+        index_of_device(substr, false)
     }
 }
 
+pub struct MidiInDevices;
+impl MidiInDevices {
+    pub fn index_of(substr: &str) -> Option<i32> {
+        index_of_device(substr, true)
+    }
+}
+
+pub trait MidiSink {
+    fn send(&mut self, m: &MidiMessage) -> Result<(), MidiError>;
+    fn send_sys_ex(&mut self, data: &[u8]) -> Result<(), MidiError>;
+
+    // blocks until everything sent so far has gone out, for sinks that queue
+    fn flush(&mut self) -> Result<(), MidiError> {
+        Ok(())
+    }
+}
+
+
 pub struct MidiOut {
     ostream: *const c_void
 }
 
+// the stream is only ever used by the thread that currently owns the MidiOut
+unsafe impl Send for MidiOut {}
+
 impl MidiOut {
-    pub fn using_device(id: i32) -> MidiOut {
-        unsafe { Pm_Initialize() };
-        let m = MidiOut {
-            ostream: ptr::null()
-        };
+    pub fn using_device(id: i32) -> Result<MidiOut, MidiError> {
+        let mut ostream: *const c_void = ptr::null();
         let buffer_size: c_int = 1024;
-        let res = unsafe { Pm_OpenOutput(&m.ostream, id, ptr::null(), buffer_size, ptr::null(), ptr::null(), 0) };
-        println!("opening output: {}", res as i32);
+        let res = unsafe { Pm_OpenOutput(&mut ostream, id, ptr::null(), buffer_size, ptr::null(), ptr::null(), 0) };
+        checked(res)?;
+        println!("opened output {}", id);
         thread::sleep(Duration::from_millis(1000));
-        m
+        Ok(MidiOut { ostream })
     }
+}
 
-    pub fn send(&mut self, m: &MidiMessage) {
-        unsafe { Pm_WriteShort(self.ostream, 0, m.as_u32()) };
+impl MidiSink for MidiOut {
+    fn send(&mut self, m: &MidiMessage) -> Result<(), MidiError> {
+        checked(unsafe { Pm_WriteShort(self.ostream, 0, m.as_u32()) })
     }
 
-    pub fn send_sys_ex(&mut self, data: &[u8]) {
-        unsafe { Pm_WriteSysEx(self.ostream, 0, data.as_ptr()) };
-        thread::sleep(Duration::from_millis(100));
+    fn send_sys_ex(&mut self, data: &[u8]) -> Result<(), MidiError> {
+        checked(unsafe { Pm_WriteSysEx(self.ostream, 0, data.as_ptr()) })
     }
 }
 
 impl Drop for MidiOut {
     fn drop(&mut self) {
         unsafe { Pm_Close(self.ostream) };
-        unsafe { Pm_Terminate() };
         println!("MidiOut closed");
     }
 }
 
 
+
+
+#[derive(Debug, PartialEq)]
+pub enum MidiInEvent {
+    Short(u32, MidiMessage),
+    SysEx(u32, Vec<u8>)
+}
+
+const SYS_EX_START: u8 = 0xF0;
+const SYS_EX_END: u8 = 0xF7;
+
+// portmidi delivers sys ex four bytes per event, least significant byte first,
+// with real-time messages allowed in between
+struct SysExAssembler {
+    timestamp: u32,
+    data: Vec<u8>
+}
+
+impl SysExAssembler {
+    fn new() -> SysExAssembler {
+        SysExAssembler {
+            timestamp: 0,
+            data: Vec::new()
+        }
+    }
+
+    fn receive(&mut self, event: &PmEvent) -> Option<MidiInEvent> {
+        let status = (event.message & 0xFF) as u8;
+        if status >= 0xF8 {
+            return Some(MidiInEvent::Short(event.timestamp, MidiMessage::from_u32(event.message)));
+        }
+        if status == SYS_EX_START {
+            self.timestamp = event.timestamp;
+            self.data.clear();
+        } else if self.data.is_empty() || (status & 0x80 != 0 && status != SYS_EX_END) {
+            self.data.clear();
+            return Some(MidiInEvent::Short(event.timestamp, MidiMessage::from_u32(event.message)));
+        }
+
+        for i in 0..4 {
+            let b = ((event.message >> (8 * i)) & 0xFF) as u8;
+            self.data.push(b);
+            if b == SYS_EX_END {
+                return Some(MidiInEvent::SysEx(self.timestamp, std::mem::take(&mut self.data)));
+            }
+        }
+        None
+    }
+}
+
+
+pub struct MidiIn {
+    istream: *const c_void,
+    sys_ex: SysExAssembler,
+    received: VecDeque<MidiInEvent>
+}
+
+unsafe impl Send for MidiIn {}
+
+impl MidiIn {
+    const BUFFER_SIZE: usize = 256;
+
+    pub fn using_device(id: i32) -> Result<MidiIn, MidiError> {
+        let mut istream: *const c_void = ptr::null();
+        let res = unsafe { Pm_OpenInput(&mut istream, id, ptr::null(), MidiIn::BUFFER_SIZE as i32, ptr::null(), ptr::null()) };
+        checked(res)?;
+        println!("opened input {}", id);
+        Ok(MidiIn {
+            istream,
+            sys_ex: SysExAssembler::new(),
+            received: VecDeque::new()
+        })
+    }
+
+    pub fn poll(&self) -> Result<bool, MidiError> {
+        let res = unsafe { Pm_Poll(self.istream) };
+        checked(res)?;
+        Ok(PmError::from(res) == PmError::PmGotData)
+    }
+
+    fn read_available(&mut self) -> Result<(), MidiError> {
+        let mut buffer = [PmEvent { message: 0, timestamp: 0 }; MidiIn::BUFFER_SIZE];
+        while self.poll()? {
+            let count = unsafe { Pm_Read(self.istream, buffer.as_mut_ptr(), MidiIn::BUFFER_SIZE as i32) };
+            if count < 0 {
+                checked(count)?;
+            }
+            for e in &buffer[..count.max(0) as usize] {
+                if let Some(received) = self.sys_ex.receive(e) {
+                    self.received.push_back(received);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn next_event(&mut self) -> Result<Option<MidiInEvent>, MidiError> {
+        if self.received.is_empty() {
+            self.read_available()?;
+        }
+        Ok(self.received.pop_front())
+    }
+
+    pub fn clear(&mut self) -> Result<(), MidiError> {
+        self.read_available()?;
+        self.received.clear();
+        Ok(())
+    }
+
+    pub fn wait_for_sys_ex(&mut self, timeout: Duration) -> Result<Option<Vec<u8>>, MidiError> {
+        let start = std::time::Instant::now();
+        while start.elapsed() < timeout {
+            match self.next_event()? {
+                Some(MidiInEvent::SysEx(_, data)) => return Ok(Some(data)),
+                Some(_) => {},
+                None => thread::sleep(Duration::from_millis(1))
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Drop for MidiIn {
+    fn drop(&mut self) {
+        unsafe { Pm_Close(self.istream) };
+        println!("MidiIn closed");
+    }
+}
+
+
+pub struct SerialOut {
+    port: Box<dyn serialport::SerialPort>
+}
+
+impl SerialOut {
+    pub fn using_port(path: &str, baud_rate: u32) -> Result<SerialOut, MidiError> {
+        let port = serialport::new(path, baud_rate)
+                    .timeout(Duration::from_millis(1000))
+                    .open()
+                    .map_err(io::Error::from)?;
+        Ok(SerialOut { port })
+    }
+}
+
+impl MidiSink for SerialOut {
+    fn send(&mut self, m: &MidiMessage) -> Result<(), MidiError> {
+        Ok(self.port.write_all(&m.as_bytes())?)
+    }
+
+    fn send_sys_ex(&mut self, data: &[u8]) -> Result<(), MidiError> {
+        Ok(self.port.write_all(data)?)
+    }
+}
+
+
+pub struct RecordingOut {
+    pub bytes: Vec<u8>,
+    file: Option<File>
+}
+
+impl RecordingOut {
+    #[allow(dead_code)]
+    pub fn in_memory() -> RecordingOut {
+        RecordingOut {
+            bytes: Vec::new(),
+            file: None
+        }
+    }
+
+    pub fn to_file(path: &str) -> RecordingOut {
+        println!("recording to {}", path);
+        RecordingOut {
+            bytes: Vec::new(),
+            file: Some(File::create(path).expect("Failed to create recording"))
+        }
+    }
+
+    fn record(&mut self, data: &[u8]) -> Result<(), MidiError> {
+        match &mut self.file {
+            Some(f) => f.write_all(data)?,
+            None => self.bytes.extend_from_slice(data)
+        }
+        Ok(())
+    }
+}
+
+impl MidiSink for RecordingOut {
+    fn send(&mut self, m: &MidiMessage) -> Result<(), MidiError> {
+        self.record(&m.as_bytes())
+    }
+
+    fn send_sys_ex(&mut self, data: &[u8]) -> Result<(), MidiError> {
+        self.record(data)
+    }
+}
+
+
+#[cfg(test)]
+mod test_sys_ex_assembler {
+    use super::{MidiInEvent, MidiMessage, PmEvent, SysExAssembler};
+
+    fn event(message: u32, timestamp: u32) -> PmEvent {
+        PmEvent { message, timestamp }
+    }
+
+    #[test]
+    fn passes_short_messages_straight_through() {
+        let mut assembler = SysExAssembler::new();
+
+        let received = assembler.receive(&event(0x403C90, 12));
+
+        assert_eq!(received, Some(MidiInEvent::Short(12, MidiMessage::from_u32(0x403C90))));
+    }
+
+    #[test]
+    fn reassembles_sys_ex_spread_over_several_events() {
+        let mut assembler = SysExAssembler::new();
+
+        assert_eq!(assembler.receive(&event(0x363042F0, 5)), None);
+        let received = assembler.receive(&event(0x00F74001, 6));
+
+        assert_eq!(received, Some(MidiInEvent::SysEx(5, vec![0xF0, 0x42, 0x30, 0x36, 0x01, 0x40, 0xF7])));
+    }
+
+    #[test]
+    fn emits_real_time_messages_received_during_sys_ex() {
+        let mut assembler = SysExAssembler::new();
+
+        assembler.receive(&event(0x163041F0, 1));
+        let clock = assembler.receive(&event(0xF8, 2));
+        let received = assembler.receive(&event(0x0000F711, 3));
+
+        assert_eq!(clock, Some(MidiInEvent::Short(2, MidiMessage::from_u32(0xF8))));
+        assert_eq!(received, Some(MidiInEvent::SysEx(1, vec![0xF0, 0x41, 0x30, 0x16, 0x11, 0xF7])));
+    }
+}
+
+
+#[cfg(test)]
+mod test_recording {
+    use super::{MidiMessage, MidiSink, RecordingOut};
+
+    #[test]
+    fn records_program_change_as_two_bytes() {
+        let mut out = RecordingOut::in_memory();
+
+        out.send(&MidiMessage::program(33, 0)).unwrap();
+
+        assert_eq!(out.bytes, [0xC0, 33]);
+    }
+
+    #[test]
+    fn records_sys_ex_verbatim_after_previous_messages() {
+        let mut out = RecordingOut::in_memory();
+
+        out.send(&MidiMessage::program(1, 2)).unwrap();
+        out.send_sys_ex(&[0xF0, 0x42, 0xF7]).unwrap();
+
+        assert_eq!(out.bytes, [0xC2, 1, 0xF0, 0x42, 0xF7]);
+    }
+}
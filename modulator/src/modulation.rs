@@ -1,15 +1,57 @@
 use std::f32;
 use std::collections::HashMap;
+use rand::{Rng, rngs::StdRng};
+use json::{object, JsonValue};
 
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shape {
+    Cosine,
+    Triangle,
+    SawUp,
+    SawDown,
+    Square,
+    SampleAndHold,
+    SmoothedRandom,
+    RandomWalk
+}
+
+// phase is a fraction of a cycle, and a sweep that follows another shares its frequency so the two stay in (or out of) phase
+#[derive(Clone, Copy)]
+pub struct Lfo<'a> {
+    pub shape: Shape,
+    pub phase: f32,
+    pub follow: Option<&'a str>
+}
+
+pub const COSINE: Lfo = Lfo { shape: Shape::Cosine, phase: 0.0, follow: None };
+
+
+#[derive(Clone, Copy)]
 pub enum Updater<'a> {
     Const(&'a str, i8),
     PairedInverseConst(&'a str, i8),
-    Sweep(&'a str, i8, i8),
+    Sweep(&'a str, i16, i16), // a single byte parameter, so -128 to 255
+    ShapedSweep(&'a str, i16, i16, Lfo<'a>),
+    WideSweep(&'a str, i16, i16), // a double byte parameter
     PairedInverseSweep(&'a str),
     SelectOnZero(&'a str)
 }
 
+impl Updater<'_> {
+    pub fn name(&self) -> &str {
+        match self {
+            Updater::Const(n, _) |
+            Updater::PairedInverseConst(n, _) |
+            Updater::Sweep(n, _, _) |
+            Updater::ShapedSweep(n, _, _, _) |
+            Updater::WideSweep(n, _, _) |
+            Updater::PairedInverseSweep(n) |
+            Updater::SelectOnZero(n) => n
+        }
+    }
+}
+
 
 pub trait SysExComposer {
     fn data(&mut self, d: i8);
@@ -21,32 +63,106 @@ pub trait Selector {
     fn next1(&mut self);
     fn next2(&mut self);
 
+    // odd numbers are chosen along with the first, even with the second
+    fn next(&mut self, idx: u8) {
+        if idx % 2 == 1 {
+            self.next1();
+        } else {
+            self.next2();
+        }
+    }
+
     fn val(&self, idx: u8) -> u16;
 }
 
 pub struct SweepState {
-    pub val: i8, // public so the app can print it
-    prev_val: i8,
-    freq_hz: f32
+    pub val: i16, // public so the app can print it
+    prev_val: i16,
+    freq_hz: f32,
+    cycle: i64, // the random shapes pick new levels on each cycle
+    from_level: f32,
+    to_level: f32,
+    at_ms: f32
 }
 
 impl SweepState {
-    fn from(val: i8, freq_hz: f32) -> SweepState {
+    fn from(val: i16, freq_hz: f32) -> SweepState {
         SweepState {
-            val, prev_val: val, freq_hz
+            val, prev_val: val, freq_hz, cycle: -1, from_level: 1.0, to_level: 1.0, at_ms: 0.0
         }
     }
 
-    fn updated_from(previous: &SweepState, val: i8) -> SweepState {
+    fn updated_from(previous: &SweepState, val: i16) -> SweepState {
         SweepState {
-            val, prev_val: previous.val, freq_hz: previous.freq_hz
+            val, prev_val: previous.val, ..previous.clone()
+        }
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        object!{
+            val: self.val,
+            prev_val: self.prev_val,
+            freq_hz: self.freq_hz,
+            cycle: self.cycle,
+            from_level: self.from_level,
+            to_level: self.to_level,
+            at_ms: self.at_ms
+        }
+    }
+
+    pub fn from_json(j: &JsonValue) -> Option<SweepState> {
+        Some(SweepState {
+            val: j["val"].as_i16()?,
+            prev_val: j["prev_val"].as_i16()?,
+            freq_hz: j["freq_hz"].as_f32()?,
+            cycle: j["cycle"].as_i64().unwrap_or(-1),
+            from_level: j["from_level"].as_f32().unwrap_or(1.0),
+            to_level: j["to_level"].as_f32().unwrap_or(1.0),
+            at_ms: j["at_ms"].as_f32().unwrap_or(0.0)
+        })
+    }
+
+    // position between min (0.0) and max (1.0) at the given time
+    fn next_level(&mut self, rng: &mut StdRng, lfo: &Lfo, at_ms: f32, min: i16, max: i16) -> f32 {
+        let cycles = at_ms * 0.001 * self.freq_hz + lfo.phase;
+        let p = cycles.rem_euclid(1.0);
+        if cycles.floor() as i64 != self.cycle {
+            self.cycle = cycles.floor() as i64;
+            self.from_level = self.to_level;
+            self.to_level = rng.gen::<f32>();
+        }
+        let dt = (at_ms - self.at_ms).max(0.0) * 0.001;
+        self.at_ms = at_ms;
+
+        match lfo.shape {
+            Shape::Cosine => 0.5 * (1.0 + (p * 2.0 * f32::consts::PI).cos()),
+            Shape::Triangle => (1.0 - 2.0 * p).abs(),
+            Shape::SawUp => p,
+            Shape::SawDown => 1.0 - p,
+            Shape::Square => if p < 0.5 { 1.0 } else { 0.0 },
+            Shape::SampleAndHold => self.to_level,
+            Shape::SmoothedRandom => self.from_level + (self.to_level - self.from_level) * 0.5 * (1.0 - (p * f32::consts::PI).cos()),
+            Shape::RandomWalk => {
+                // steps scaled so the walk typically wanders about half the range each cycle
+                let current = if max == min { 0.0 } else { (self.val - min) as f32 / (max - min) as f32 };
+                let step = (2.0 * rng.gen::<f32>() - 1.0) * 0.5 * (3.0 * dt * self.freq_hz).sqrt();
+                (current + step).clamp(0.0, 1.0)
+            }
         }
     }
 }
 
 impl Clone for SweepState {
     fn clone(&self) -> Self {
-        SweepState { val: self.val, prev_val: self.prev_val, freq_hz: self.freq_hz }
+        SweepState {
+            val: self.val,
+            prev_val: self.prev_val,
+            freq_hz: self.freq_hz,
+            cycle: self.cycle,
+            from_level: self.from_level,
+            to_level: self.to_level,
+            at_ms: self.at_ms
+        }
     }
 }
 
@@ -57,49 +173,97 @@ pub trait StepInterval {
 
 pub struct PairedUpdater<'a> {
     pub sweep_state: HashMap::<String, SweepState>,
-    interval: &'a dyn StepInterval
+    interval: &'a dyn StepInterval,
+    rng: StdRng
 }
 
 impl PairedUpdater<'_> {
     const ALTERNATOR: &'static str = "alternator";
-    const ALTERNATOR_MAX: i8 = 99;
+    const ALTERNATOR_MAX: i16 = 99;
 
-    fn random_frequency() -> f32 {
-        let r = rand::random::<f64>();
+    fn random_frequency(rng: &mut StdRng) -> f32 {
+        let r = rng.gen::<f64>();
         0.01 + (r / 100.0) as f32
     }
 
-    fn random_between(min: i8, max: i8) -> i8 {
-        min + (0.5 + ((max - min) as f32 * rand::random::<f32>())) as i8
+    fn random_between(rng: &mut StdRng, min: i16, max: i16) -> i16 {
+        min + (0.5 + ((max - min) as f32 * rng.gen::<f32>())) as i16
     }
 
-    fn next_val_from(interval: &dyn StepInterval, freq_hz: f32, min: i8, max: i8) -> i8 {
+    fn next_val_from(interval: &dyn StepInterval, freq_hz: f32, min: i16, max: i16) -> i16 {
         let dt = interval.interval();
-        let ang_freq = freq_hz * 2.0 * f32::consts::PI as f32;
-        (min as f32 + ((max as f32 - min as f32) * 0.5 * (1.0 + (dt * 0.001 * ang_freq).cos()))).round() as i8
+        let ang_freq = freq_hz * 2.0 * f32::consts::PI;
+        (min as f32 + ((max as f32 - min as f32) * 0.5 * (1.0 + (dt * 0.001 * ang_freq).cos()))).round() as i16
     }
 
-    pub fn new(interval: &dyn StepInterval) -> PairedUpdater {
+    fn sweep(&mut self, key: &str, prefix: Option<&str>, min: i16, max: i16, lfo: &Lfo) -> i16 {
+        let key_of = |k: &str| match prefix {
+            Some(p) => [p, k].join("_"),
+            None => String::from(k)
+        };
+        let followed_freq = lfo.follow.and_then(|f| self.sweep_state.get(&key_of(f))).map(|f| f.freq_hz);
+
+        let rng = &mut self.rng;
+        let state_val = self.sweep_state.entry(key_of(key)).or_insert_with(|| SweepState::from(max, PairedUpdater::random_frequency(rng)));
+        if let Some(freq_hz) = followed_freq {
+            state_val.freq_hz = freq_hz;
+        }
+        let level = state_val.next_level(rng, lfo, self.interval.interval(), min, max);
+        let new_val = (min as f32 + (max - min) as f32 * level).round() as i16;
+        *state_val = SweepState::updated_from(state_val, new_val);
+        new_val
+    }
+
+    pub fn new(interval: &dyn StepInterval, mut rng: StdRng) -> PairedUpdater<'_> {
+        let alternator = SweepState::from(PairedUpdater::random_between(&mut rng, 0, PairedUpdater::ALTERNATOR_MAX), PairedUpdater::random_frequency(&mut rng));
         let mut p = PairedUpdater {
             sweep_state: HashMap::<String, SweepState>::new(),
-            interval
+            interval,
+            rng
         };
-        p.sweep_state.insert(PairedUpdater::ALTERNATOR.to_string(), SweepState::from(PairedUpdater::random_between(0, PairedUpdater::ALTERNATOR_MAX), PairedUpdater::random_frequency()));
+        p.sweep_state.insert(PairedUpdater::ALTERNATOR.to_string(), alternator);
         p
     }
 
+    pub fn snapshot(&self) -> JsonValue {
+        let mut j = JsonValue::new_object();
+        for (key, state) in &self.sweep_state {
+            j[key.as_str()] = state.to_json();
+        }
+        j
+    }
+
+    // all or nothing: the sweeps are left as they were unless every one in the snapshot is whole
+    pub fn restore(&mut self, j: &JsonValue) -> Result<(), String> {
+        if j[PairedUpdater::ALTERNATOR].is_null() {
+            return Err(String::from("snapshot has no alternator"));
+        }
+        let restored = j.entries()
+            .map(|(key, state)| SweepState::from_json(state).map(|s| (key.to_string(), s)).ok_or(format!("sweep {} is incomplete", key)))
+            .collect::<Result<Vec<_>, String>>()?;
+        self.sweep_state.extend(restored);
+        Ok(())
+    }
+
     pub fn sweep_alternator(&mut self) {
-        let v = self.sweep_state.get_mut(&PairedUpdater::ALTERNATOR.to_string()).unwrap();
-        let nv = PairedUpdater::next_val_from(&*self.interval, v.freq_hz, 0, PairedUpdater::ALTERNATOR_MAX);
-        *v = SweepState::updated_from(&v, nv);
+        let v = self.sweep_state.get_mut(PairedUpdater::ALTERNATOR).unwrap();
+        let nv = PairedUpdater::next_val_from(self.interval, v.freq_hz, 0, PairedUpdater::ALTERNATOR_MAX);
+        *v = SweepState::updated_from(v, nv);
+    }
+
+    // whether the last sweep of the alternator took it to the end that changes the odd (or even) selections
+    pub fn alternator_reached(&self, idx: u8) -> bool {
+        let v = self.sweep_state.get(PairedUpdater::ALTERNATOR).unwrap();
+        let test_v = if idx.is_multiple_of(2) { PairedUpdater::ALTERNATOR_MAX } else { 0 };
+        v.val == test_v && v.prev_val != test_v
     }
 
-    pub fn update<'a, S: SysExComposer, O: Selector, E: Selector>(
+    pub fn update<S: SysExComposer, O: Selector, E: Selector>(
         &mut self,
         sys_ex: &mut S,
         osc_selector: &mut O,
         effect_selector: &mut E,
-        updaters: &'a [Updater],
+        updaters: &[Updater],
         prefix: Option<&str>)
     {
         for u in updaters {
@@ -108,45 +272,39 @@ impl PairedUpdater<'_> {
                     sys_ex.data(*c);
                 },
                 Updater::PairedInverseConst(_, c) => {
-                    let inverse = '2' == prefix.unwrap().chars().last().unwrap();
+                    let inverse = prefix.unwrap().ends_with('2');
                     sys_ex.data(if inverse { *c } else { 0 });
                 },
                 Updater::Sweep(key, min, max) => {
-                    let s = if prefix.is_none() { String::from(*key) } else { [prefix.unwrap(), *key].join("_") };
-
-                    let state_val = self.sweep_state.entry(s).or_insert(SweepState::from(*max, PairedUpdater::random_frequency()));
-                    let new_val = PairedUpdater::next_val_from(&*self.interval, state_val.freq_hz, *min, *max);
-                    *state_val = SweepState::updated_from(&state_val, new_val);
-                    sys_ex.data(new_val);
+                    let new_val = self.sweep(key, prefix, *min, *max, &COSINE);
+                    sys_ex.data(new_val as i8);
+                },
+                Updater::ShapedSweep(key, min, max, lfo) => {
+                    let new_val = self.sweep(key, prefix, *min, *max, lfo);
+                    sys_ex.data(new_val as i8);
+                },
+                Updater::WideSweep(key, min, max) => {
+                    let new_val = self.sweep(key, prefix, *min, *max, &COSINE);
+                    sys_ex.data_double_byte(new_val);
                 },
                 Updater::PairedInverseSweep(_) => {
                     let idx = prefix.unwrap().chars().last().unwrap().to_digit(10).unwrap() as u8;
-                    let inverse = (idx % 2) == 0;
-                    let v = self.sweep_state.get(&PairedUpdater::ALTERNATOR.to_string()).unwrap();
+                    let inverse = idx.is_multiple_of(2);
+                    let v = self.sweep_state.get(PairedUpdater::ALTERNATOR).unwrap();
                     if inverse {
-                        sys_ex.data(PairedUpdater::ALTERNATOR_MAX - v.val);
+                        sys_ex.data((PairedUpdater::ALTERNATOR_MAX - v.val) as i8);
                     } else {
-                        sys_ex.data(v.val);
+                        sys_ex.data(v.val as i8);
                     }
                 },
                 Updater::SelectOnZero(key) => {
-                    let idx = key.chars().last().unwrap().to_digit(10).unwrap() as u8;
-                    let inverse = (idx % 2) == 0;
-
-                    let v = self.sweep_state.get(&PairedUpdater::ALTERNATOR.to_string()).unwrap();
-                    let test_v = if inverse { PairedUpdater::ALTERNATOR_MAX } else { 0 };
-
-                    if v.val == test_v && v.prev_val != test_v {
-                        if 1 == idx {
-                            osc_selector.next1();
-                            effect_selector.next1();
-                            println!("new eff1 {}", effect_selector.val(1));
-                        } else {
-                            osc_selector.next2();
-                            effect_selector.next2();
-                            println!("new eff2 {}", effect_selector.val(2));
-                        }
-                        println!("{} change {}", key, osc_selector.val(idx));
+                    // the Korg's oscillators are numbered in the key, the D110's partials in the prefix
+                    let digit = |s: &str| s.chars().last().and_then(|c| c.to_digit(10));
+                    let idx = digit(key).or_else(|| prefix.and_then(digit)).unwrap() as u8;
+
+                    if self.alternator_reached(idx) {
+                        osc_selector.next(idx);
+                        effect_selector.next(idx);
                     }
                     sys_ex.data_double_byte(osc_selector.val(idx) as i16);
                 }
@@ -154,3 +312,214 @@ impl PairedUpdater<'_> {
         }
     }
 }
+
+
+// fixed and moving clocks, a composer that keeps the bytes and a selector that never changes, for the tests below
+#[cfg(test)]
+mod test_support {
+    use super::{StepInterval, SysExComposer, Selector};
+    use std::cell::Cell;
+
+    pub struct At(pub f32);
+
+    impl StepInterval for At {
+        fn interval(&self) -> f32 { self.0 }
+    }
+
+    pub struct Clock(pub Cell<f32>);
+
+    impl StepInterval for Clock {
+        fn interval(&self) -> f32 { self.0.get() }
+    }
+
+    pub struct Bytes(pub Vec<i8>);
+
+    impl SysExComposer for Bytes {
+        fn data(&mut self, d: i8) { self.0.push(d); }
+        fn data_double_byte(&mut self, d: i16) { self.0.push(d as i8); self.0.push((d >> 8) as i8); }
+        fn name(&mut self, _: &str) {}
+    }
+
+    pub struct NoSelection;
+
+    impl Selector for NoSelection {
+        fn next1(&mut self) {}
+        fn next2(&mut self) {}
+        fn val(&self, _: u8) -> u16 { 0 }
+    }
+}
+
+
+#[cfg(test)]
+mod test_shapes {
+    use super::{Lfo, Shape, SweepState, PairedUpdater, Updater, COSINE};
+    use super::test_support::{At, Bytes, NoSelection};
+    use rand::{SeedableRng, rngs::StdRng};
+
+    fn level_at(shape: Shape, phase: f32, at_ms: f32) -> f32 {
+        // 1Hz so the phase is the fraction of a second
+        SweepState::from(0, 1.0).next_level(&mut StdRng::seed_from_u64(1), &Lfo { shape, phase, follow: None }, at_ms, 0, 100)
+    }
+
+    #[test]
+    fn periodic_shapes_start_at_max() {
+        for shape in [Shape::Cosine, Shape::Triangle, Shape::SawDown, Shape::Square] {
+            assert_eq!(level_at(shape, 0.0, 0.0), 1.0);
+        }
+    }
+
+    #[test]
+    fn periodic_shapes_a_quarter_cycle_in() {
+        assert!((level_at(Shape::Cosine, 0.0, 250.0) - 0.5).abs() < 0.0001);
+        assert_eq!(level_at(Shape::Triangle, 0.0, 250.0), 0.5);
+        assert_eq!(level_at(Shape::SawUp, 0.0, 250.0), 0.25);
+        assert_eq!(level_at(Shape::SawDown, 0.0, 250.0), 0.75);
+        assert_eq!(level_at(Shape::Square, 0.0, 750.0), 0.0);
+    }
+
+    #[test]
+    fn phase_offsets_shift_the_cycle() {
+        assert_eq!(level_at(Shape::SawUp, 0.5, 250.0), 0.75);
+        assert_eq!(level_at(Shape::Triangle, 0.5, 0.0), 0.0);
+    }
+
+    #[test]
+    fn sample_and_hold_holds_for_the_cycle() {
+        let mut state = SweepState::from(0, 1.0);
+        let mut rng = StdRng::seed_from_u64(1);
+        let lfo = Lfo { shape: Shape::SampleAndHold, phase: 0.0, follow: None };
+
+        let first = state.next_level(&mut rng, &lfo, 100.0, 0, 100);
+        let same_cycle = state.next_level(&mut rng, &lfo, 900.0, 0, 100);
+
+        assert_eq!(first, same_cycle);
+        assert!((0.0..=1.0).contains(&first));
+    }
+
+    #[test]
+    fn random_shapes_stay_in_range() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for shape in [Shape::SmoothedRandom, Shape::RandomWalk] {
+            let mut state = SweepState::from(50, 0.5);
+            let lfo = Lfo { shape, phase: 0.0, follow: None };
+            for t in 0..1000 {
+                let level = state.next_level(&mut rng, &lfo, t as f32 * 100.0, 0, 100);
+                assert!((0.0..=1.0).contains(&level));
+            }
+        }
+    }
+
+    #[test]
+    fn sweep_following_another_out_of_phase_moves_in_the_opposite_direction() {
+        let spec = [
+            Updater::Sweep("filter", 0, 100),
+            Updater::ShapedSweep("amp", 0, 100, Lfo { phase: 0.5, follow: Some("filter"), ..COSINE })
+        ];
+        for at in [0.0, 3000.0, 17000.0, 41000.0] {
+            let interval = At(at);
+            let mut updater = PairedUpdater::new(&interval, StdRng::seed_from_u64(at as u64));
+            let mut bytes = Bytes(Vec::new());
+
+            updater.update(&mut bytes, &mut NoSelection, &mut NoSelection, &spec, Some("osc1"));
+
+            assert!((bytes.0[0] as i16 + bytes.0[1] as i16 - 100).abs() <= 1);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test_seeding {
+    use super::{PairedUpdater, Updater, Lfo, Shape};
+    use super::test_support::{Bytes, Clock, NoSelection};
+    use rand::{SeedableRng, rngs::StdRng};
+    use std::cell::Cell;
+
+    const SPEC: [Updater; 4] = [
+        Updater::Sweep("a", -50, 50),
+        Updater::Sweep("b", 0, 99),
+        Updater::ShapedSweep("c", 0, 99, Lfo { shape: Shape::RandomWalk, phase: 0.0, follow: None }),
+        Updater::PairedInverseSweep("vol")
+    ];
+
+    fn session(seed: u64) -> Vec<i8> {
+        let mut bytes = Bytes(Vec::new());
+        let clock = Clock(Cell::new(0.0));
+        let mut updater = PairedUpdater::new(&clock, StdRng::seed_from_u64(seed));
+        for step in 0..50 {
+            clock.0.set(1000.0 * step as f32);
+            updater.update(&mut bytes, &mut NoSelection, &mut NoSelection, &SPEC, Some("osc1"));
+            updater.sweep_alternator();
+        }
+        bytes.0
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_session() {
+        assert_eq!(session(42), session(42));
+    }
+
+    #[test]
+    fn different_seeds_give_different_sessions() {
+        assert_ne!(session(42), session(43));
+    }
+}
+
+
+#[cfg(test)]
+mod test_snapshot {
+    use super::{PairedUpdater, Updater};
+    use super::test_support::{At, Bytes, NoSelection};
+    use rand::{SeedableRng, rngs::StdRng};
+
+    const SPEC: [Updater; 3] = [
+        Updater::Sweep("a", -50, 50),
+        Updater::Sweep("b", 0, 99),
+        Updater::PairedInverseSweep("vol")
+    ];
+
+    #[test]
+    fn restored_updater_continues_where_the_snapshot_left_off() {
+        let interval = At(12345.0);
+        let mut original = PairedUpdater::new(&interval, StdRng::seed_from_u64(1));
+        original.update(&mut Bytes(Vec::new()), &mut NoSelection, &mut NoSelection, &SPEC, Some("osc1"));
+        original.sweep_alternator();
+        let snapshot = json::parse(&original.snapshot().dump()).unwrap();
+
+        let later = At(20000.0);
+        let mut resumed = PairedUpdater::new(&later, StdRng::seed_from_u64(2));
+        resumed.restore(&snapshot).unwrap();
+        assert_eq!(resumed.sweep_state["osc1_a"].val, original.sweep_state["osc1_a"].val);
+        let mut continued = PairedUpdater::new(&later, StdRng::seed_from_u64(3));
+        continued.restore(&original.snapshot()).unwrap();
+
+        let (mut from_resumed, mut from_original) = (Bytes(Vec::new()), Bytes(Vec::new()));
+        resumed.update(&mut from_resumed, &mut NoSelection, &mut NoSelection, &SPEC, Some("osc1"));
+        continued.update(&mut from_original, &mut NoSelection, &mut NoSelection, &SPEC, Some("osc1"));
+
+        assert_eq!(from_resumed.0, from_original.0);
+    }
+
+    #[test]
+    fn rejects_a_snapshot_without_the_alternator() {
+        let interval = At(0.0);
+        let mut updater = PairedUpdater::new(&interval, StdRng::seed_from_u64(1));
+
+        assert!(updater.restore(&json::parse(r#"{"a": {"val": 1, "prev_val": 1, "freq_hz": 0.01}}"#).unwrap()).is_err());
+    }
+
+    #[test]
+    fn leaves_the_sweeps_alone_when_any_is_incomplete() {
+        let interval = At(0.0);
+        let mut updater = PairedUpdater::new(&interval, StdRng::seed_from_u64(1));
+        let before = updater.snapshot().dump();
+        let snapshot = json::parse(r#"{
+            "alternator": {"val": 3, "prev_val": 2, "freq_hz": 0.01},
+            "a": {"val": 1, "prev_val": 1, "freq_hz": 0.01},
+            "b": {"val": 1}
+        }"#).unwrap();
+
+        assert_eq!(updater.restore(&snapshot).unwrap_err(), "sweep b is incomplete");
+        assert_eq!(updater.snapshot().dump(), before);
+    }
+}
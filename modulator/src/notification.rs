@@ -0,0 +1,127 @@
+use std::io::{BufRead, Read};
+
+
+// what thru posts to port 7878 when it drops a note: {"note": 60}, and perhaps "velocity"
+#[derive(Debug, Default, PartialEq)]
+pub struct PlayNotification {
+    pub note: Option<u8>,
+    pub velocity: Option<u8>
+}
+
+impl PlayNotification {
+    const MAX_STEP_MS: f32 = 4000.0;
+    // far more than a note needs, and all the listener will take from a client
+    const MAX_HEADER_LINE: u64 = 1024;
+    const MAX_HEADERS: usize = 64;
+    const MAX_BODY: usize = 1024;
+
+    // an empty body is a bare notification, as sent by the clock or older versions of thru
+    pub fn from_body(body: &str) -> Result<PlayNotification, String> {
+        if body.trim().is_empty() {
+            return Ok(PlayNotification::default());
+        }
+        let parsed = json::parse(body).map_err(|e| format!("{}", e))?;
+        let field = |name: &str| match &parsed[name] {
+            v if v.is_null() => Ok(None),
+            v => v.as_u8().filter(|n| *n < 0x80).map(Some).ok_or(format!("{} is {} but must be 0 to 127", name, v))
+        };
+        Ok(PlayNotification {
+            note: field("note")?,
+            velocity: field("velocity")?
+        })
+    }
+
+    // reads the headers and the body they give the length of, leaving the reader at the next request
+    pub fn read(reader: &mut dyn BufRead) -> Result<PlayNotification, String> {
+        let mut length = 0;
+        for headers in 0.. {
+            if headers > PlayNotification::MAX_HEADERS {
+                return Err(format!("more than {} header lines", PlayNotification::MAX_HEADERS));
+            }
+            let mut line = String::new();
+            let read = reader.take(PlayNotification::MAX_HEADER_LINE).read_line(&mut line).map_err(|e| format!("{}", e))?;
+            if read == 0 {
+                break;
+            }
+            if !line.ends_with('\n') {
+                return Err(format!("header line over {} bytes", PlayNotification::MAX_HEADER_LINE));
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("content-length") {
+                    length = value.trim().parse::<usize>().map_err(|_| format!("bad Content-Length {}", value.trim()))?;
+                }
+            }
+        }
+
+        if length > PlayNotification::MAX_BODY {
+            return Err(format!("body of {} bytes is over {}", length, PlayNotification::MAX_BODY));
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).map_err(|e| format!("{}", e))?;
+        PlayNotification::from_body(&String::from_utf8_lossy(&body))
+    }
+
+    // low, middle and high registers each drive a part of their own; without a note they all move
+    pub fn part(&self, parts: usize) -> Option<usize> {
+        self.note.map(|n| match n {
+            0..=47 => 0,
+            48..=71 => 1,
+            _ => 2
+        }.min(parts - 1))
+    }
+
+    // how much further than the clock a note pushes the sweeps, the harder the further
+    pub fn step_ms(&self) -> f32 {
+        self.velocity.map_or(0.0, |v| PlayNotification::MAX_STEP_MS * v as f32 / 127.0)
+    }
+}
+
+
+#[cfg(test)]
+mod test_notification {
+    use super::PlayNotification;
+
+    #[test]
+    fn reads_the_note_and_velocity_from_the_body() {
+        let request = "POST / HTTP/1.1\r\nContent-type: application/json\r\ncontent-length: 25\r\n\r\n{\"note\":61,\"velocity\":90}";
+        let n = PlayNotification::read(&mut request.as_bytes()).unwrap();
+        assert_eq!(n, PlayNotification { note: Some(61), velocity: Some(90) });
+        assert_eq!(n.part(3), Some(1));
+    }
+
+    #[test]
+    fn takes_a_request_without_a_body_as_a_bare_notification() {
+        let n = PlayNotification::read(&mut "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n".as_bytes()).unwrap();
+        assert_eq!(n, PlayNotification::default());
+        assert_eq!(n.part(3), None);
+        assert_eq!(n.step_ms(), 0.0);
+    }
+
+    #[test]
+    fn refuses_long_bodies_and_header_lines() {
+        let request = "POST / HTTP/1.1\r\nContent-Length: 100000000\r\n\r\n{}";
+        assert_eq!(PlayNotification::read(&mut request.as_bytes()).unwrap_err(), "body of 100000000 bytes is over 1024");
+
+        let request = format!("POST / HTTP/1.1\r\nX-Padding: {}\r\n\r\n", "x".repeat(2000));
+        assert_eq!(PlayNotification::read(&mut request.as_bytes()).unwrap_err(), "header line over 1024 bytes");
+    }
+
+    #[test]
+    fn rejects_notes_out_of_range() {
+        assert!(PlayNotification::from_body("{\"note\": 128}").is_err());
+        assert!(PlayNotification::from_body("{\"note\": 60, \"velocity\": \"loud\"}").is_err());
+        assert!(PlayNotification::from_body("note=60").is_err());
+    }
+
+    #[test]
+    fn maps_registers_to_parts() {
+        let part = |note| PlayNotification { note: Some(note), velocity: None }.part(3).unwrap();
+        assert_eq!([part(36), part(60), part(84)], [0, 1, 2]);
+        assert_eq!(PlayNotification { note: Some(84), velocity: None }.part(2), Some(1));
+        assert_eq!(PlayNotification { note: None, velocity: Some(127) }.step_ms(), 4000.0);
+    }
+}
@@ -0,0 +1,235 @@
+use crate::midi::{MidiError, MidiMessage, MidiSink};
+use std::{
+    collections::VecDeque,
+    sync::{mpsc::{self, Sender, Receiver}, Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant}
+};
+
+
+pub const MIDI_BAUD: u32 = 31250;
+
+// what an output can carry: the line speed, and how long the device needs to absorb each sys ex
+#[derive(Clone, Copy)]
+pub struct Link {
+    pub name: &'static str,
+    pub baud: u32,
+    pub sys_ex_gap: Duration
+}
+
+impl Link {
+    // a start and a stop bit for every byte
+    pub fn time_for(&self, bytes: usize) -> Duration {
+        Duration::from_micros(bytes as u64 * 10 * 1_000_000 / self.baud as u64)
+    }
+}
+
+pub const D110_LINK: Link = Link { name: "D110", baud: MIDI_BAUD, sys_ex_gap: Duration::from_millis(40) }; // safe for the older MT-32 ROMs too
+pub const KORG_MIDI_LINK: Link = Link { name: "Korg", baud: MIDI_BAUD, sys_ex_gap: Duration::from_millis(20) };
+pub const KORG_SERIAL_LINK: Link = Link { name: "Korg serial", baud: 38400, sys_ex_gap: Duration::from_millis(20) };
+pub const FM_LINK: Link = Link { name: "FM", baud: MIDI_BAUD, sys_ex_gap: Duration::from_millis(50) };
+pub const CONTROLLER_LINK: Link = Link { name: "controllers", baud: MIDI_BAUD, sys_ex_gap: Duration::ZERO };
+
+enum Outgoing {
+    Message(MidiMessage),
+    SysEx(Vec<u8>),
+    Flush(Sender<()>)
+}
+
+impl Outgoing {
+    // only notes may overtake; program changes and controllers keep their place among the sys ex
+    fn is_note(&self) -> bool {
+        matches!(self, Outgoing::Message(m) if matches!(m.status & 0xF0, 0x80 | 0x90 | 0xA0))
+    }
+}
+
+struct Queued {
+    outgoing: Outgoing,
+    at: Instant
+}
+
+// how many messages other than notes are waiting to go
+type Pending = Arc<(Mutex<usize>, Condvar)>;
+
+
+// notes go ahead of any sys ex already queued, which is sent in order at the pace the link allows;
+// once MAX_QUEUED are waiting, the sender waits too, so modulation can't build up a backlog
+pub struct ScheduledOut {
+    tx: Sender<Queued>,
+    pending: Pending
+}
+
+impl ScheduledOut {
+    const BEHIND: Duration = Duration::from_millis(500);
+    const REPORT_EVERY: Duration = Duration::from_secs(1);
+    const MAX_QUEUED: usize = 16;
+
+    pub fn new(link: Link, sink: Box<dyn MidiSink + Send>) -> ScheduledOut {
+        let (tx, rx) = mpsc::channel();
+        let pending = Arc::new((Mutex::new(0), Condvar::new()));
+        let sent = Arc::clone(&pending);
+        thread::spawn(move || ScheduledOut::transmit(link, sink, rx, sent));
+        ScheduledOut { tx, pending }
+    }
+
+    fn transmit(link: Link, mut sink: Box<dyn MidiSink + Send>, rx: Receiver<Queued>, pending: Pending) {
+        let mut notes = VecDeque::new();
+        let mut modulation = VecDeque::new();
+        let mut last_report = Instant::now() - ScheduledOut::REPORT_EVERY;
+        let enqueue = |q: Queued, notes: &mut VecDeque<Queued>, modulation: &mut VecDeque<Queued>| {
+            if q.outgoing.is_note() {
+                notes.push_back(q);
+            } else {
+                modulation.push_back(q);
+            }
+        };
+
+        loop {
+            if notes.is_empty() && modulation.is_empty() {
+                match rx.recv() {
+                    Ok(q) => enqueue(q, &mut notes, &mut modulation),
+                    Err(_) => return
+                }
+            }
+            while let Ok(q) = rx.try_recv() {
+                enqueue(q, &mut notes, &mut modulation);
+            }
+
+            let next = notes.pop_front().or_else(|| modulation.pop_front()).unwrap();
+            let (res, wait) = match &next.outgoing {
+                Outgoing::Message(m) => (sink.send(m), link.time_for(m.byte_count())),
+                Outgoing::SysEx(data) => (sink.send_sys_ex(data), link.time_for(data.len()) + link.sys_ex_gap),
+                Outgoing::Flush(done) => (done.send(()).map_err(|_| MidiError::Closed), Duration::ZERO)
+            };
+            if let Err(e) = res {
+                println!("{} send failed: {}", link.name, e);
+            }
+            thread::sleep(wait);
+            if !next.outgoing.is_note() {
+                let (count, freed) = &*pending;
+                *count.lock().unwrap() -= 1;
+                freed.notify_all();
+            }
+
+            if let Some(oldest) = modulation.front() {
+                if oldest.at.elapsed() > ScheduledOut::BEHIND && last_report.elapsed() > ScheduledOut::REPORT_EVERY {
+                    println!("{} modulation falling behind: {} messages queued, oldest {} ms", link.name, modulation.len(), oldest.at.elapsed().as_millis());
+                    last_report = Instant::now();
+                }
+            }
+        }
+    }
+
+    fn queue(&self, outgoing: Outgoing) -> Result<(), MidiError> {
+        if !outgoing.is_note() {
+            let (count, freed) = &*self.pending;
+            let mut count = freed.wait_while(count.lock().unwrap(), |c| *c >= ScheduledOut::MAX_QUEUED).unwrap();
+            *count += 1;
+        }
+        self.tx.send(Queued { outgoing, at: Instant::now() }).map_err(|_| MidiError::Closed)
+    }
+}
+
+impl MidiSink for ScheduledOut {
+    fn send(&mut self, m: &MidiMessage) -> Result<(), MidiError> {
+        self.queue(Outgoing::Message(m.clone()))
+    }
+
+    fn send_sys_ex(&mut self, data: &[u8]) -> Result<(), MidiError> {
+        self.queue(Outgoing::SysEx(data.to_vec()))
+    }
+
+    fn flush(&mut self) -> Result<(), MidiError> {
+        let (done_tx, done_rx) = mpsc::channel();
+        self.queue(Outgoing::Flush(done_tx))?;
+        done_rx.recv().map_err(|_| MidiError::Closed)
+    }
+}
+
+
+#[cfg(test)]
+mod test_scheduler {
+    use super::{Link, ScheduledOut};
+    use crate::midi::{MidiError, MidiMessage, MidiSink};
+    use std::{sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+    struct Shared(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl MidiSink for Shared {
+        fn send(&mut self, m: &MidiMessage) -> Result<(), MidiError> {
+            self.0.lock().unwrap().push(m.as_bytes());
+            Ok(())
+        }
+
+        fn send_sys_ex(&mut self, data: &[u8]) -> Result<(), MidiError> {
+            self.0.lock().unwrap().push(data.to_vec());
+            Ok(())
+        }
+    }
+
+    const FAST: Link = Link { name: "test", baud: 1_000_000, sys_ex_gap: Duration::from_millis(30) };
+
+    #[test]
+    fn times_bytes_at_ten_bits_each() {
+        assert_eq!(super::D110_LINK.time_for(202), Duration::from_micros(64640));
+        assert_eq!(super::KORG_SERIAL_LINK.time_for(10), Duration::from_micros(2604));
+    }
+
+    #[test]
+    fn sends_notes_ahead_of_queued_sys_ex() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let mut out = ScheduledOut::new(FAST, Box::new(Shared(Arc::clone(&sent))));
+
+        for n in 1..4 {
+            out.send_sys_ex(&[0xF0, n, 0xF7]).unwrap();
+        }
+        out.send(&MidiMessage::from_u32(0x7F3C90)).unwrap();
+        out.flush().unwrap();
+
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 4);
+        assert!(sent.iter().position(|m| *m == [0x90, 0x3C, 0x7F]).unwrap() < 2); // the first may already have gone
+        assert_eq!(sent[3], [0xF0, 3, 0xF7]);
+    }
+
+    #[test]
+    fn keeps_program_changes_in_order_with_sys_ex() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let mut out = ScheduledOut::new(FAST, Box::new(Shared(Arc::clone(&sent))));
+
+        out.send_sys_ex(&[0xF0, 2, 0xF7]).unwrap();
+        out.send(&MidiMessage::program(33, 0)).unwrap();
+        out.flush().unwrap();
+
+        assert_eq!(*sent.lock().unwrap(), [vec![0xF0, 2, 0xF7], vec![0xC0, 33]]);
+    }
+
+    #[test]
+    fn holds_the_sender_back_once_the_queue_is_full() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let mut out = ScheduledOut::new(FAST, Box::new(Shared(Arc::clone(&sent))));
+        let start = Instant::now();
+
+        for n in 0..ScheduledOut::MAX_QUEUED as u8 + 2 {
+            out.send_sys_ex(&[0xF0, n, 0xF7]).unwrap();
+        }
+
+        // the last had to wait for the first two to go
+        assert!(start.elapsed() >= Duration::from_millis(30));
+        assert!(sent.lock().unwrap().len() >= 2);
+    }
+
+    #[test]
+    fn paces_sys_ex_by_the_device_gap() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let mut out = ScheduledOut::new(FAST, Box::new(Shared(Arc::clone(&sent))));
+        let start = Instant::now();
+
+        for n in 0..3 {
+            out.send_sys_ex(&[0xF0, n, 0xF7]).unwrap();
+        }
+        out.flush().unwrap();
+
+        assert!(start.elapsed() >= Duration::from_millis(90));
+    }
+}
@@ -0,0 +1,343 @@
+use crate::d110::{D110Reply, D110ReplyError, D110Request, D110SysEx, linear_address};
+use crate::korg::{KorgProgramSysEx, CHANNEL};
+use crate::midi::{MidiError, MidiMessage, MidiSink};
+use crate::modulation::SysExComposer;
+use std::collections::{BTreeMap, VecDeque};
+
+
+// anything between the start and end of a sys ex must be a data byte
+fn check_framing(msg: &[u8]) -> Result<(), String> {
+    if msg.len() < 6 || msg[0] != 0xF0 || msg[msg.len() - 1] != 0xF7 {
+        return Err(String::from("not a complete sys ex"));
+    }
+    match msg[1..msg.len() - 1].iter().position(|b| *b > 0x7F) {
+        Some(at) => Err(format!("status byte {:02X} inside the sys ex at {}", msg[at + 1], at + 1)),
+        None => Ok(())
+    }
+}
+
+
+// stands in for a D-110 on the other end of the cable: DT1 writes land in its memory and RQ1 requests are answered with a DT1
+pub struct D110Simulator {
+    memory: BTreeMap<u32, u8>, // by linear address
+    replies: VecDeque<Vec<u8>>,
+    pub rejected: Vec<String>
+}
+
+impl D110Simulator {
+    pub fn new() -> D110Simulator {
+        D110Simulator {
+            memory: BTreeMap::new(),
+            replies: VecDeque::new(),
+            rejected: Vec::new()
+        }
+    }
+
+    // memory that was never written reads as 0
+    pub fn read(&self, address: u32, size: u32) -> Vec<u8> {
+        let start = linear_address(address);
+        (start..start + size).map(|a| *self.memory.get(&a).unwrap_or(&0)).collect()
+    }
+
+    pub fn next_reply(&mut self) -> Option<Vec<u8>> {
+        self.replies.pop_front()
+    }
+
+    fn receive(&mut self, msg: &[u8]) -> Result<(), String> {
+        check_framing(msg)?;
+        match D110Reply::parse(msg) {
+            Ok(written) => {
+                let start = linear_address(written.address);
+                for (i, v) in written.data.iter().enumerate() {
+                    self.memory.insert(start + i as u32, *v);
+                }
+                Ok(())
+            },
+            Err(D110ReplyError::NotD110Dt1) => {
+                let request = D110Request::parse(msg).map_err(|e| format!("{:?}", e))?;
+                let data = self.read(request.address, request.size);
+                self.replies.push_back(D110SysEx::write(request.address, &data).to_send());
+                Ok(())
+            },
+            Err(e) => Err(format!("{:?}", e))
+        }
+    }
+}
+
+impl MidiSink for D110Simulator {
+    fn send(&mut self, _: &MidiMessage) -> Result<(), MidiError> {
+        Ok(())
+    }
+
+    // a rejected message is ignored, as the device would ignore it, and noted for the caller to check
+    fn send_sys_ex(&mut self, data: &[u8]) -> Result<(), MidiError> {
+        if let Err(e) = self.receive(data) {
+            self.rejected.push(e);
+        }
+        Ok(())
+    }
+}
+
+
+// stands in for an 05R/W: program dumps and single parameter changes are applied to its edit buffer,
+// which a program dump request returns. the layout numbers the parameters from the 05R/W table in korg.rs
+pub struct KorgSimulator {
+    layout: BTreeMap<u16, (usize, usize)>,
+    program: Vec<u8>, // as the 05R/W stores it, with the MSBs restored
+    replies: VecDeque<Vec<u8>>,
+    pub mode: Option<u8>,
+    pub rejected: Vec<String>
+}
+
+impl KorgSimulator {
+    pub fn new(layout: BTreeMap<u16, (usize, usize)>) -> KorgSimulator {
+        KorgSimulator {
+            layout,
+            program: vec![0; KorgProgramSysEx::PROGRAM_SIZE],
+            replies: VecDeque::new(),
+            mode: None,
+            rejected: Vec::new()
+        }
+    }
+
+    pub fn next_reply(&mut self) -> Option<Vec<u8>> {
+        self.replies.pop_front()
+    }
+
+    fn dump(&self) -> Vec<u8> {
+        let mut dump = KorgProgramSysEx::new();
+        for b in &self.program {
+            dump.data(*b as i8);
+        }
+        dump.data.to_vec()
+    }
+
+    // values are 14 bit two's complement, stored low byte first
+    fn change(&mut self, p: u16, v: u16) -> Result<(), String> {
+        let (offset, width) = *self.layout.get(&p).ok_or(format!("no parameter {}", p))?;
+        let v = ((v << 2) as i16) >> 2;
+        self.program[offset] = v as u8;
+        if width == 2 {
+            self.program[offset + 1] = (v >> 8) as u8;
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, msg: &[u8]) -> Result<(), String> {
+        check_framing(msg)?;
+        if msg[1..4] != [0x42, 0x30 | CHANNEL, 0x36] {
+            return Err(format!("not for an 05R/W on channel {}: {:02X?}", CHANNEL + 1, &msg[1..4]));
+        }
+        match (msg[4], msg.len()) {
+            (0x40, _) => self.program = KorgProgramSysEx::from_dump(msg).ok_or("program dump of the wrong length")?.program(),
+            (0x41, 10) => self.change(msg[5] as u16 | (msg[6] as u16) << 7, msg[7] as u16 | (msg[8] as u16) << 7)?,
+            (0x4E, 8) => self.mode = Some(msg[5]),
+            (0x10, 6) => self.replies.push_back(self.dump()),
+            (function, len) => return Err(format!("function {:02X} with {} bytes", function, len))
+        }
+        Ok(())
+    }
+}
+
+impl MidiSink for KorgSimulator {
+    fn send(&mut self, _: &MidiMessage) -> Result<(), MidiError> {
+        Ok(())
+    }
+
+    fn send_sys_ex(&mut self, data: &[u8]) -> Result<(), MidiError> {
+        if let Err(e) = self.receive(data) {
+            self.rejected.push(e);
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test_simulators {
+    use super::{D110Simulator, KorgSimulator};
+    use crate::d110::{address_of, linear_address, seven_bit_address, D110Memory, D110SysEx, D110WaveSelector, D110, PARTIAL_PREFIXES, PARTIAL_SIZE, PARTIAL_SPEC, RHYTHM_KEYS, RHYTHM_SPEC, SYSTEM_SPEC, TONE_HEADER_SIZE};
+    use crate::korg::{param_layout, KorgEffectSelector, KorgOscSelector, KorgParamDeltas, KorgProgram, KorgProgramDumpRequest, KorgProgramSysEx, KorgSingleParamSysEx, AVAILABLE_EFFECTS, OSC_SPEC, PROGRAM_SPEC};
+    use crate::library::D110Library;
+    use crate::spec::byte_length;
+    use crate::midi::MidiSink;
+    use crate::modulation::{PairedUpdater, Selector, StepInterval, SysExComposer, Updater};
+    use crate::{update_d110, update_d110_rhythm, update_d110_system, update_korg, KorgSpecs, NUM_D110_PARTS};
+    use rand::{SeedableRng, rngs::StdRng};
+    use std::{cell::Cell, sync::Mutex};
+
+    struct Stepped(Cell<f32>);
+
+    impl StepInterval for Stepped {
+        fn interval(&self) -> f32 {
+            self.0.get()
+        }
+    }
+
+    fn within(u: &Updater, v: i16) -> bool {
+        match u {
+            Updater::Const(_, c) => v == *c as i16,
+            Updater::PairedInverseConst(_, c) => v == 0 || v == *c as i16,
+            Updater::Sweep(_, min, max) | Updater::ShapedSweep(_, min, max, _) | Updater::WideSweep(_, min, max) => (*min..=*max).contains(&v),
+            Updater::PairedInverseSweep(_) => (0..=99).contains(&v),
+            Updater::SelectOnZero(_) => true
+        }
+    }
+
+    #[test]
+    fn d110_applies_writes_and_answers_requests() {
+        let mut d110 = D110Simulator::new();
+        d110.send_sys_ex(&D110SysEx::write(0x040205, &[1, 2, 3]).to_send()).unwrap();
+        d110.send_sys_ex(&D110SysEx::request(0x040204, 5).to_send()).unwrap();
+
+        assert_eq!(d110.next_reply(), Some(D110SysEx::write(0x040204, &[0, 1, 2, 3, 0]).to_send()));
+        assert!(d110.rejected.is_empty());
+    }
+
+    #[test]
+    fn d110_rejects_bad_checksums_and_other_devices() {
+        let mut d110 = D110Simulator::new();
+        let mut msg = D110SysEx::write(0x040205, &[1, 2, 3]).to_send();
+        msg[8] = 4;
+        d110.send_sys_ex(&msg).unwrap();
+        msg[3] = 0x14;
+        d110.send_sys_ex(&msg).unwrap();
+        d110.send_sys_ex(&[0xF0, 0x41, 0x10, 0x16, 0x12, 0x04, 0x02, 0x05, 0x81, 0x74, 0xF7]).unwrap();
+
+        assert_eq!(d110.rejected.len(), 3);
+        assert_eq!(d110.read(0x040205, 3), [0, 0, 0]);
+    }
+
+    #[test]
+    fn korg_applies_dumps_and_parameter_changes() {
+        let mut korg = KorgSimulator::new(param_layout());
+        let mut program = KorgProgramSysEx::new();
+        program.name("simulated ");
+        for _ in 0..byte_length(&PROGRAM_SPEC) + 2 * byte_length(&OSC_SPEC) {
+            program.data(0);
+        }
+        let effects = KorgEffectSelector::new(&AVAILABLE_EFFECTS, StdRng::seed_from_u64(1));
+        for u in effects.pre_eff().iter().chain(&effects.eff1.updater).chain(&effects.eff2.updater) {
+            program.data(if let Updater::Const(_, c) = u { *c } else { 0 });
+        }
+        korg.send_sys_ex(&program.data).unwrap();
+        korg.send_sys_ex(&KorgSingleParamSysEx::new(2, 300).data).unwrap();
+        korg.send_sys_ex(&KorgSingleParamSysEx::new(9, -7).data).unwrap();
+        korg.send_sys_ex(&[0xF0, 0x42, 0x31, 0x36, 0x41, 0, 0, 1, 0, 0xF7]).unwrap();
+        korg.send_sys_ex(&KorgSingleParamSysEx::new(19, 1).data).unwrap(); // bit packed, so not in the table
+        korg.send_sys_ex(&KorgProgramDumpRequest::new().data).unwrap();
+
+        let dumped = KorgProgram::from_dump(&korg.next_reply().unwrap(), &AVAILABLE_EFFECTS).unwrap();
+        assert_eq!(dumped.name, "simulated");
+        assert_eq!(dumped.value("detune"), Some(-7));
+        assert_eq!(dumped.value("osc1"), Some(300));
+        assert_eq!(korg.rejected.len(), 2);
+    }
+
+    #[test]
+    fn keeps_d110_partials_within_spec() {
+        let mut d110 = D110Simulator::new();
+        let library = Mutex::new(D110Library::new());
+        let mut memory = D110Memory::new();
+        for msg in D110.setup() {
+            d110.send_sys_ex(&msg.to_send()).unwrap();
+            memory.record(&msg);
+        }
+
+        let interval = Stepped(Cell::new(0.0));
+        let mut updater = PairedUpdater::new(&interval, StdRng::seed_from_u64(1));
+        let mut system_updater = PairedUpdater::new(&interval, StdRng::seed_from_u64(2));
+        let mut waves = (1..4).map(|t| D110WaveSelector::new(&format!("tone{}", t), StdRng::seed_from_u64(t))).collect::<Vec<_>>();
+        for cycle in 0..200 {
+            interval.0.set(cycle as f32 * 1000.0);
+            update_d110(&mut updater, &PARTIAL_SPEC, &mut waves, 0..NUM_D110_PARTS, &mut d110, &library, &mut memory);
+            update_d110_system(&mut system_updater, &SYSTEM_SPEC, &mut d110, &library, &mut memory);
+            update_d110_rhythm(&mut system_updater, &RHYTHM_SPEC, &mut d110, &library, &mut memory);
+        }
+
+        assert!(d110.rejected.is_empty(), "{:?}", d110.rejected);
+        for tone in 1..4 {
+            for p in 0..PARTIAL_PREFIXES.len() as u32 {
+                let start = linear_address(address_of(tone)) + TONE_HEADER_SIZE + p * PARTIAL_SIZE;
+                let partial = d110.read(seven_bit_address(start), PARTIAL_SIZE);
+                let mut at = 0;
+                for u in PARTIAL_SPEC {
+                    assert!(within(&u, partial[at] as i16), "tone {} partial {} {} = {}", tone, p + 1, u.name(), partial[at]);
+                    at += byte_length(&[u]);
+                }
+                assert!(partial[4] < 4 && partial[5] < 128);
+            }
+        }
+
+        let system = d110.read(0x100001, SYSTEM_SPEC.len() as u32);
+        for (u, v) in SYSTEM_SPEC.iter().zip(system) {
+            assert!(within(u, v as i16), "{} = {}", u.name(), v);
+        }
+        let rhythm = d110.read(0x030110, 4 * RHYTHM_KEYS as u32);
+        for (i, v) in rhythm.iter().enumerate() {
+            let u = &RHYTHM_SPEC[i % 4];
+            assert!(within(u, *v as i16), "key {} {} = {}", 24 + i / 4, u.name(), v);
+        }
+    }
+
+    #[test]
+    fn changes_the_waves_of_tones_not_written_at_the_switchover() {
+        let mut d110 = D110Simulator::new();
+        let library = Mutex::new(D110Library::new());
+        let mut memory = D110Memory::new();
+        let interval = Stepped(Cell::new(0.0));
+        let mut updater = PairedUpdater::new(&interval, StdRng::seed_from_u64(1));
+        let mut waves = (1..4).map(|t| D110WaveSelector::new(&format!("tone{}", t), StdRng::seed_from_u64(t))).collect::<Vec<_>>();
+        let before = [1, 3, 2, 4].map(|idx| waves[2].val(idx));
+
+        // only the lowest tone is played
+        for cycle in 0..200 {
+            interval.0.set(cycle as f32 * 1000.0);
+            update_d110(&mut updater, &PARTIAL_SPEC, &mut waves, 0..1, &mut d110, &library, &mut memory);
+        }
+
+        assert_ne!([1, 3, 2, 4].map(|idx| waves[2].val(idx)), before);
+    }
+
+    fn run_korg(deltas: bool) -> (KorgSimulator, Vec<u8>) {
+        let specs = KorgSpecs { program: PROGRAM_SPEC.to_vec(), osc: OSC_SPEC.to_vec(), effects: AVAILABLE_EFFECTS.to_vec() };
+        let interval = Stepped(Cell::new(0.0));
+        let mut updater = PairedUpdater::new(&interval, StdRng::seed_from_u64(1));
+        let mut osc_selector = KorgOscSelector::new(StdRng::seed_from_u64(2));
+        let mut effect_selector = KorgEffectSelector::new(&specs.effects, StdRng::seed_from_u64(3));
+        let mut param_deltas = KorgParamDeltas::new();
+        let mut korg = KorgSimulator::new(param_layout());
+
+        for cycle in 0..200 {
+            interval.0.set(cycle as f32 * 700.0);
+            update_korg(&mut korg, &mut updater, &mut osc_selector, &mut effect_selector, &specs, "simulated ", deltas.then_some(&mut param_deltas));
+        }
+        korg.send_sys_ex(&KorgProgramDumpRequest::new().data).unwrap();
+        let dump = korg.next_reply().unwrap();
+        (korg, dump)
+    }
+
+    #[test]
+    fn keeps_korg_programs_within_spec() {
+        let (korg, dump) = run_korg(false);
+        let program = KorgProgram::from_dump(&dump, &AVAILABLE_EFFECTS).unwrap();
+
+        assert!(korg.rejected.is_empty(), "{:?}", korg.rejected);
+        for (prefix, spec) in [(None, &PROGRAM_SPEC[..]), (Some("osc1"), &OSC_SPEC[..]), (Some("osc2"), &OSC_SPEC[..])] {
+            for u in spec.iter().filter(|u| !u.name().is_empty()) {
+                let key = prefix.map_or(u.name().to_string(), |p| format!("{}_{}", p, u.name()));
+                let v = program.value(&key).unwrap();
+                assert!(within(u, v), "{} = {}", key, v);
+            }
+        }
+    }
+
+    #[test]
+    fn deltas_leave_the_same_program_as_full_dumps() {
+        let (korg, deltas_dump) = run_korg(true);
+        let (_, full_dump) = run_korg(false);
+
+        assert!(korg.rejected.is_empty(), "{:?}", korg.rejected);
+        assert_eq!(deltas_dump, full_dump);
+    }
+}
@@ -0,0 +1,262 @@
+use crate::modulation::{Updater, Lfo, Shape};
+use json::JsonValue;
+use std::path::Path;
+
+
+pub struct SpecFile {
+    pub path: String,
+    json: Option<JsonValue>
+}
+
+impl SpecFile {
+    pub fn load(dir: Option<&str>, name: &str) -> Result<SpecFile, String> {
+        let path = match dir {
+            Some(d) => Path::new(d).join(name).to_string_lossy().to_string(),
+            None => name.to_string()
+        };
+        if dir.is_none() || !Path::new(&path).exists() {
+            return Ok(SpecFile { path, json: None });
+        }
+        let contents = std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+        let json = json::parse(&contents).map_err(|e| format!("{}: {}", path, e))?;
+        println!("using {}", path);
+        Ok(SpecFile { path, json: Some(json) })
+    }
+
+    pub fn json(&self) -> Option<&JsonValue> {
+        self.json.as_ref()
+    }
+
+    pub fn updaters<'a>(&'a self, default: &[Updater<'a>]) -> Result<Vec<Updater<'a>>, String> {
+        match &self.json {
+            Some(j) => updaters_from(j, default).map_err(|e| format!("{}: {}", self.path, e)),
+            None => Ok(default.to_vec())
+        }
+    }
+}
+
+
+// the number of bytes a spec writes into the device's block
+pub fn byte_length(spec: &[Updater]) -> usize {
+    spec.iter().map(|u| match u {
+        Updater::SelectOnZero(_) | Updater::WideSweep(_, _, _) => 2,
+        _ => 1
+    }).sum()
+}
+
+fn lfo_from(j: &JsonValue) -> Result<Lfo<'_>, String> {
+    let shape = match j["shape"].as_str().unwrap_or("cosine") {
+        "cosine" => Shape::Cosine,
+        "triangle" => Shape::Triangle,
+        "sawUp" => Shape::SawUp,
+        "sawDown" => Shape::SawDown,
+        "square" => Shape::Square,
+        "sampleAndHold" => Shape::SampleAndHold,
+        "smoothedRandom" => Shape::SmoothedRandom,
+        "randomWalk" => Shape::RandomWalk,
+        s => return Err(format!("unknown shape {}", s))
+    };
+    let phase = if j["phase"].is_null() { 0.0 } else { j["phase"].as_f32().ok_or("phase must be a number")? };
+    Ok(Lfo { shape, phase, follow: j["follow"].as_str() })
+}
+
+pub fn updater_from(j: &JsonValue) -> Result<Updater<'_>, String> {
+    let name = j["name"].as_str().ok_or(format!("updater without a name: {}", j.dump()))?;
+    let int = |key: &str| j[key].as_i8().ok_or(format!("{}: {} missing or not an i8", name, key));
+    let range = |lowest: i16, highest: i16| {
+        let (min, max) = (j["min"].as_i16(), j["max"].as_i16());
+        match (min, max) {
+            (Some(min), Some(max)) if lowest <= min && min <= max && max <= highest => Ok((min, max)),
+            _ => Err(format!("{}: sweep needs {} <= min <= max <= {}", name, lowest, highest))
+        }
+    };
+    match j["type"].as_str() {
+        Some("const") => Ok(Updater::Const(name, int("value")?)),
+        Some("pairedInverseConst") => Ok(Updater::PairedInverseConst(name, int("value")?)),
+        Some("sweep") => {
+            let (min, max) = range(-128, 255)?;
+            if j["shape"].is_null() && j["phase"].is_null() && j["follow"].is_null() {
+                return Ok(Updater::Sweep(name, min, max));
+            }
+            Ok(Updater::ShapedSweep(name, min, max, lfo_from(j).map_err(|e| format!("{}: {}", name, e))?))
+        },
+        Some("wideSweep") => {
+            let (min, max) = range(i16::MIN, i16::MAX)?;
+            Ok(Updater::WideSweep(name, min, max))
+        },
+        Some("pairedInverseSweep") => Ok(Updater::PairedInverseSweep(name)),
+        Some("selectOnZero") => Ok(Updater::SelectOnZero(name)),
+        t => Err(format!("{}: unknown updater type {:?}", name, t))
+    }
+}
+
+// a loaded spec must fill exactly the same device block as the built in one it replaces
+pub fn updaters_from<'a>(j: &'a JsonValue, default: &[Updater]) -> Result<Vec<Updater<'a>>, String> {
+    if !j.is_array() {
+        return Err(String::from("expected an array of updaters"));
+    }
+    let spec = j.members().map(updater_from).collect::<Result<Vec<Updater>, String>>()?;
+    if byte_length(&spec) != byte_length(default) {
+        return Err(format!("fills {} bytes but the device block is {}", byte_length(&spec), byte_length(default)));
+    }
+    Ok(spec)
+}
+
+
+// the values a device accepts for each parameter a spec may name. paired and selecting updaters take their
+// values from the alternator and selectors rather than the spec, so only the others need to be listed
+pub struct ParamLimits {
+    pub device: &'static str,
+    pub params: &'static [(&'static str, i16, i16)]
+}
+
+impl ParamLimits {
+    fn range(&self, name: &str) -> Option<(i16, i16)> {
+        self.params.iter().find(|(n, _, _)| *n == name).map(|(_, lowest, highest)| (*lowest, *highest))
+    }
+
+    fn needs_range(u: &Updater) -> bool {
+        !matches!(u, Updater::PairedInverseSweep(_) | Updater::SelectOnZero(_))
+    }
+
+    fn not_listed(&self, u: &Updater) -> String {
+        format!("{} is not a parameter of the {}", u.name(), self.device)
+    }
+
+    // a line for each updater naming a parameter the device doesn't list, which clamping can't fix
+    pub fn unknown(&self, spec: &[Updater]) -> Vec<String> {
+        spec.iter().filter(|u| ParamLimits::needs_range(u) && self.range(u.name()).is_none()).map(|u| self.not_listed(u)).collect()
+    }
+
+    // a line for each updater that would send a value the device doesn't accept, or names one it doesn't have
+    pub fn check(&self, spec: &[Updater]) -> Vec<String> {
+        spec.iter().filter(|u| ParamLimits::needs_range(u)).filter_map(|u| {
+            let Some((lowest, highest)) = self.range(u.name()) else {
+                return Some(self.not_listed(u));
+            };
+            let legal = |v: i16| (lowest..=highest).contains(&v);
+            match u {
+                Updater::Const(n, c) | Updater::PairedInverseConst(n, c) if !legal(*c as i16) =>
+                    Some(format!("{} is {} but the {} takes {} to {}", n, c, self.device, lowest, highest)),
+                Updater::Sweep(n, min, max) | Updater::ShapedSweep(n, min, max, _) | Updater::WideSweep(n, min, max) if !legal(*min) || !legal(*max) =>
+                    Some(format!("{} sweeps {} to {} but the {} takes {} to {}", n, min, max, self.device, lowest, highest)),
+                _ => None
+            }
+        }).collect()
+    }
+
+    // pulls each value the device doesn't accept to the nearest one it does
+    pub fn clamp(&self, spec: &mut [Updater]) {
+        for u in spec.iter_mut() {
+            if let Some((lowest, highest)) = self.range(u.name()) {
+                match u {
+                    Updater::Const(_, c) | Updater::PairedInverseConst(_, c) => *c = (*c as i16).clamp(lowest, highest) as i8,
+                    Updater::Sweep(_, min, max) | Updater::ShapedSweep(_, min, max, _) | Updater::WideSweep(_, min, max) => {
+                        *min = (*min).clamp(lowest, highest);
+                        *max = (*max).clamp(lowest, highest);
+                    },
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test_spec {
+    use super::{updaters_from, byte_length, ParamLimits};
+    use crate::modulation::{Updater, Lfo, Shape};
+
+    const DEFAULT: [Updater; 3] = [
+        Updater::Const("a", 1),
+        Updater::SelectOnZero("osc1"),
+        Updater::Sweep("b", 1, 10)
+    ];
+
+    #[test]
+    fn counts_select_on_zero_and_wide_sweep_as_two_bytes() {
+        assert_eq!(byte_length(&DEFAULT), 4);
+        assert_eq!(byte_length(&[Updater::WideSweep("a", 0, 1000)]), 2);
+    }
+
+    #[test]
+    fn parses_each_updater_type() {
+        let j = json::parse(r#"[
+            {"type": "sweep", "name": "a", "min": -5, "max": 5},
+            {"type": "const", "name": "b", "value": 3},
+            {"type": "pairedInverseConst", "name": "c", "value": -103},
+            {"type": "pairedInverseSweep", "name": "vol"}
+        ]"#).unwrap();
+        let wide = json::parse(r#"[{"type": "wideSweep", "name": "d", "min": 0, "max": 1000}, {"type": "const", "name": "e", "value": 0}, {"type": "sweep", "name": "f", "min": 64, "max": 200}]"#).unwrap();
+        let shaped = json::parse(r#"[{"type": "sweep", "name": "g", "min": 0, "max": 9, "shape": "sawDown", "phase": 0.5, "follow": "f"}, {"type": "const", "name": "h", "value": 0}, {"type": "const", "name": "i", "value": 0}, {"type": "const", "name": "j", "value": 0}]"#).unwrap();
+
+        let spec = updaters_from(&j, &DEFAULT).unwrap();
+
+        assert!(matches!(spec[0], Updater::Sweep("a", -5, 5)));
+        assert!(matches!(spec[1], Updater::Const("b", 3)));
+        assert!(matches!(spec[2], Updater::PairedInverseConst("c", -103)));
+        assert!(matches!(spec[3], Updater::PairedInverseSweep("vol")));
+        assert!(matches!(updaters_from(&wide, &DEFAULT).unwrap()[..], [Updater::WideSweep("d", 0, 1000), _, Updater::Sweep("f", 64, 200)]));
+        assert!(matches!(updaters_from(&shaped, &DEFAULT).unwrap()[0], Updater::ShapedSweep("g", 0, 9, Lfo { shape: Shape::SawDown, phase, follow: Some("f") }) if phase == 0.5));
+    }
+
+    #[test]
+    fn rejects_specs_that_do_not_fill_the_device_block() {
+        let j = json::parse(r#"[{"type": "const", "name": "a", "value": 1}]"#).unwrap();
+
+        assert!(updaters_from(&j, &DEFAULT).is_err());
+    }
+
+    #[test]
+    fn rejects_out_of_range_and_inverted_sweeps() {
+        // padded to DEFAULT's four bytes, so only the sweep is wrong
+        let spec = |sweep: &str| json::parse(&format!(r#"[{}, {{"type": "selectOnZero", "name": "osc1"}}, {{"type": "const", "name": "b", "value": 1}}]"#, sweep)).unwrap();
+        let too_big = spec(r#"{"type": "sweep", "name": "a", "min": 0, "max": 256}"#);
+        let inverted = spec(r#"{"type": "sweep", "name": "a", "min": 10, "max": 0}"#);
+
+        assert!(updaters_from(&spec(r#"{"type": "sweep", "name": "a", "min": 0, "max": 255}"#), &DEFAULT).is_ok());
+        assert_eq!(updaters_from(&too_big, &DEFAULT).err().unwrap(), "a: sweep needs -128 <= min <= max <= 255");
+        assert_eq!(updaters_from(&inverted, &DEFAULT).err().unwrap(), "a: sweep needs -128 <= min <= max <= 255");
+    }
+
+    #[test]
+    fn reports_and_clamps_values_outside_the_device_limits() {
+        let limits = ParamLimits { device: "test", params: &[("a", 0, 5), ("b", 2, 8), ("c", -99, 99)] };
+        let mut spec = [Updater::Const("a", 7), Updater::SelectOnZero("osc1"), Updater::Sweep("b", 1, 8), Updater::Sweep("c", -50, 50)];
+
+        assert_eq!(limits.check(&spec), ["a is 7 but the test takes 0 to 5", "b sweeps 1 to 8 but the test takes 2 to 8"]);
+        limits.clamp(&mut spec);
+
+        assert!(matches!(spec, [Updater::Const("a", 5), Updater::SelectOnZero("osc1"), Updater::Sweep("b", 2, 8), Updater::Sweep("c", -50, 50)]));
+        assert!(limits.check(&spec).is_empty());
+    }
+
+    #[test]
+    fn reports_parameters_the_device_does_not_list() {
+        let limits = ParamLimits { device: "test", params: &[("a", 0, 5)] };
+        let spec = [Updater::Const("a", 1), Updater::SelectOnZero("osc1"), Updater::Sweep("cutof", 0, 5), Updater::PairedInverseSweep("vol")];
+
+        assert_eq!(limits.unknown(&spec), ["cutof is not a parameter of the test"]);
+        assert_eq!(limits.check(&spec), ["cutof is not a parameter of the test"]);
+    }
+
+    #[test]
+    fn built_in_specs_are_within_their_device_limits() {
+        use crate::{d110, korg, yamaha::FmModel};
+
+        assert_eq!(d110::PARTIAL_LIMITS.check(&d110::PARTIAL_SPEC), Vec::<String>::new());
+        for profile in d110::DEVICE_PROFILES {
+            assert_eq!(profile.rhythm_limits.check(&d110::RHYTHM_SPEC), Vec::<String>::new());
+        }
+        assert_eq!(korg::PROGRAM_LIMITS.check(&korg::PROGRAM_SPEC), Vec::<String>::new());
+        assert_eq!(korg::OSC_LIMITS.check(&korg::OSC_SPEC), Vec::<String>::new());
+        for effect in korg::AVAILABLE_EFFECTS {
+            assert_eq!(korg::EFFECT_LIMITS.check(&effect.updater), Vec::<String>::new());
+        }
+        for model in [FmModel::Dx7, FmModel::Tx81z] {
+            assert_eq!(model.operator_limits().check(model.operator_spec()), Vec::<String>::new());
+            assert_eq!(model.voice_limits().check(model.voice_spec()), Vec::<String>::new());
+        }
+    }
+}
@@ -5,3 +5,8 @@ pub fn today() -> String {
     let now: DateTime<Utc> = SystemTime::now().into();
     now.format("%Y-%m-%d").to_string()
 }
+
+pub fn now() -> String {
+    let now: DateTime<Utc> = SystemTime::now().into();
+    now.format("%Y-%m-%d_%H%M%S").to_string()
+}
@@ -0,0 +1,374 @@
+use crate::modulation::{
+    SysExComposer,
+    Updater
+};
+use crate::spec::ParamLimits;
+
+pub const CHANNEL: u8 = 0;
+
+const SYS_EX_START: u8 = 0xF0;
+const SYS_EX_END: u8 = 0xF7;
+const YAMAHA_ID: u8 = 0x43;
+const DX7_VOICE: u8 = 0x00;
+const TX81Z_VCED: u8 = 0x03;
+const TX81Z_ACED: u8 = 0x7E; // one of the universal bulk formats, named by its header
+const ACED_HEADER: &[u8; 10] = b"LM  8976AE";
+
+
+// a voice into the edit buffer; the byte count and checksum cover everything after the format
+pub struct YamahaSysEx {
+    format: u8,
+    data: Vec<u8>
+}
+
+impl YamahaSysEx {
+    pub fn dx7_voice() -> YamahaSysEx {
+        YamahaSysEx {
+            format: DX7_VOICE,
+            data: Vec::new()
+        }
+    }
+
+    pub fn tx81z_vced() -> YamahaSysEx {
+        YamahaSysEx {
+            format: TX81Z_VCED,
+            data: Vec::new()
+        }
+    }
+
+    pub fn tx81z_aced() -> YamahaSysEx {
+        YamahaSysEx {
+            format: TX81Z_ACED,
+            data: ACED_HEADER.to_vec()
+        }
+    }
+
+    fn checksum(&self) -> u8 {
+        let sum: u32 = self.data.iter().map(|d| *d as u32).sum();
+        ((128 - (sum % 128)) % 128) as u8
+    }
+
+    pub fn to_send(&self) -> Vec<u8> {
+        let count = self.data.len();
+        let mut msg = vec![
+            SYS_EX_START,
+            YAMAHA_ID,
+            CHANNEL, // substatus 0 (bulk dump), channel
+            self.format,
+            ((count >> 7) & 0x7F) as u8,
+            (count & 0x7F) as u8
+        ];
+        msg.extend(&self.data);
+        msg.push(self.checksum());
+        msg.push(SYS_EX_END);
+        msg
+    }
+}
+
+impl SysExComposer for YamahaSysEx {
+    fn data(&mut self, d: i8) {
+        self.data.push((d as u8) & 0x7F);
+    }
+
+    fn data_double_byte(&mut self, d: i16) {
+        self.data(((d >> 7) & 0x7F) as i8);
+        self.data((d & 0x7F) as i8);
+    }
+
+    // voice names are always 10 characters
+    fn name(&mut self, n: &str) {
+        for c in format!("{:<10.10}", n).chars() {
+            self.data(c as i8);
+        }
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FmModel {
+    Dx7,
+    Tx81z
+}
+
+impl FmModel {
+    pub fn named(name: &str) -> Option<FmModel> {
+        match name.to_lowercase().as_str() {
+            "dx7" => Some(FmModel::Dx7),
+            "tx81z" => Some(FmModel::Tx81z),
+            _ => None
+        }
+    }
+
+    // in the order the operators appear in the voice data
+    pub fn operators(&self) -> &'static [&'static str] {
+        match self {
+            FmModel::Dx7 => &["op6", "op5", "op4", "op3", "op2", "op1"],
+            FmModel::Tx81z => &["op4", "op2", "op3", "op1"]
+        }
+    }
+
+    pub fn operator_spec(&self) -> &'static [Updater<'static>] {
+        match self {
+            FmModel::Dx7 => &DX7_OPERATOR_SPEC,
+            FmModel::Tx81z => &TX81Z_OPERATOR_SPEC
+        }
+    }
+
+    pub fn voice_spec(&self) -> &'static [Updater<'static>] {
+        match self {
+            FmModel::Dx7 => &DX7_VOICE_SPEC,
+            FmModel::Tx81z => &TX81Z_VOICE_SPEC
+        }
+    }
+
+    pub fn operator_limits(&self) -> &'static ParamLimits {
+        match self {
+            FmModel::Dx7 => &DX7_OPERATOR_LIMITS,
+            FmModel::Tx81z => &TX81Z_OPERATOR_LIMITS
+        }
+    }
+
+    pub fn voice_limits(&self) -> &'static ParamLimits {
+        match self {
+            FmModel::Dx7 => &DX7_VOICE_LIMITS,
+            FmModel::Tx81z => &TX81Z_VOICE_LIMITS
+        }
+    }
+}
+
+
+pub const DX7_OPERATOR_SPEC: [Updater; 21] = [
+    Updater::Sweep("env_rate1", 60, 99), // 0-99
+    Updater::Sweep("env_rate2", 20, 80),
+    Updater::Sweep("env_rate3", 20, 80),
+    Updater::Sweep("env_rate4", 30, 80),
+    Updater::Const("env_level1", 99), // 0-99
+    Updater::Sweep("env_level2", 70, 99),
+    Updater::Sweep("env_level3", 50, 90),
+    Updater::Const("env_level4", 0),
+    Updater::Const("keyboardBreakPoint", 39), // 39 = C3
+    Updater::Const("keyboardLeftDepth", 0),
+    Updater::Const("keyboardRightDepth", 0),
+    Updater::Const("keyboardLeftCurve", 0), // 0-3 -lin, -exp, +exp, +lin
+    Updater::Const("keyboardRightCurve", 0),
+    Updater::Const("rateScaling", 0), // 0-7
+    Updater::Const("ampModSens", 0), // 0-3
+    Updater::Const("keyVelocitySens", 2), // 0-7
+    Updater::Sweep("outputLevel", 60, 99), // 0-99
+    Updater::Const("oscMode", 0), // 0 = ratio, 1 = fixed
+    Updater::Sweep("freqCoarse", 0, 4), // 0-31, 0 = ratio 0.5
+    Updater::Sweep("freqFine", 0, 20), // 0-99
+    Updater::Sweep("detune", 4, 10) // 0-14, 7 = 0
+];
+
+// followed by the 10 character name
+pub const DX7_VOICE_SPEC: [Updater; 19] = [
+    Updater::Const("env_pitch_rate1", 99),
+    Updater::Const("env_pitch_rate2", 99),
+    Updater::Const("env_pitch_rate3", 99),
+    Updater::Const("env_pitch_rate4", 99),
+    Updater::Const("env_pitch_level1", 50), // 50 = no shift
+    Updater::Const("env_pitch_level2", 50),
+    Updater::Const("env_pitch_level3", 50),
+    Updater::Const("env_pitch_level4", 50),
+    Updater::Const("algorithm", 4), // 0-31
+    Updater::Sweep("feedback", 0, 7),
+    Updater::Const("oscKeySync", 1),
+    Updater::Sweep("lfoSpeed", 20, 60), // 0-99
+    Updater::Const("lfoDelay", 0),
+    Updater::Sweep("lfoPitchModDepth", 0, 10),
+    Updater::Sweep("lfoAmpModDepth", 0, 20),
+    Updater::Const("lfoKeySync", 0),
+    Updater::Const("lfoWave", 0), // 0-5 tri, saw down, saw up, square, sine, s/h
+    Updater::Const("pitchModSens", 2), // 0-7
+    Updater::Const("transpose", 24) // 24 = C3
+];
+
+pub const DX7_OPERATOR_LIMITS: ParamLimits = ParamLimits {
+    device: "DX7",
+    params: &[
+        ("env_rate1", 0, 99), ("env_rate2", 0, 99), ("env_rate3", 0, 99), ("env_rate4", 0, 99),
+        ("env_level1", 0, 99), ("env_level2", 0, 99), ("env_level3", 0, 99), ("env_level4", 0, 99),
+        ("keyboardBreakPoint", 0, 99), ("keyboardLeftDepth", 0, 99), ("keyboardRightDepth", 0, 99),
+        ("keyboardLeftCurve", 0, 3), ("keyboardRightCurve", 0, 3), ("rateScaling", 0, 7), ("ampModSens", 0, 3), ("keyVelocitySens", 0, 7),
+        ("outputLevel", 0, 99), ("oscMode", 0, 1), ("freqCoarse", 0, 31), ("freqFine", 0, 99), ("detune", 0, 14)
+    ]
+};
+
+pub const DX7_VOICE_LIMITS: ParamLimits = ParamLimits {
+    device: "DX7",
+    params: &[
+        ("env_pitch_rate1", 0, 99), ("env_pitch_rate2", 0, 99), ("env_pitch_rate3", 0, 99), ("env_pitch_rate4", 0, 99),
+        ("env_pitch_level1", 0, 99), ("env_pitch_level2", 0, 99), ("env_pitch_level3", 0, 99), ("env_pitch_level4", 0, 99),
+        ("algorithm", 0, 31), ("feedback", 0, 7), ("oscKeySync", 0, 1),
+        ("lfoSpeed", 0, 99), ("lfoDelay", 0, 99), ("lfoPitchModDepth", 0, 99), ("lfoAmpModDepth", 0, 99), ("lfoKeySync", 0, 1), ("lfoWave", 0, 5),
+        ("pitchModSens", 0, 7), ("transpose", 0, 48)
+    ]
+};
+
+pub const TX81Z_OPERATOR_SPEC: [Updater; 13] = [
+    Updater::Sweep("env_attackRate", 20, 31), // 0-31
+    Updater::Sweep("env_decay1Rate", 5, 25),
+    Updater::Sweep("env_decay2Rate", 0, 15),
+    Updater::Sweep("env_releaseRate", 4, 12), // 1-15
+    Updater::Sweep("env_decay1Level", 8, 15), // 0-15
+    Updater::Const("levelScaling", 0), // 0-99
+    Updater::Const("rateScaling", 0), // 0-3
+    Updater::Const("egBiasSens", 0), // 0-7
+    Updater::Const("ampModEnable", 0),
+    Updater::Const("keyVelocitySens", 2), // 0-7
+    Updater::Sweep("outputLevel", 70, 99), // 0-99
+    Updater::Sweep("freqCoarse", 0, 12), // 0-63, 4 = ratio 1.00
+    Updater::Sweep("detune", 1, 5) // 0-6, 3 = 0
+];
+
+// followed by the 10 character name and TX81Z_PITCH_EG_SPEC
+pub const TX81Z_VOICE_SPEC: [Updater; 25] = [
+    Updater::Const("algorithm", 4), // 0-7
+    Updater::Sweep("feedback", 0, 7),
+    Updater::Sweep("lfoSpeed", 20, 60), // 0-99
+    Updater::Const("lfoDelay", 0),
+    Updater::Sweep("lfoPitchModDepth", 0, 10),
+    Updater::Sweep("lfoAmpModDepth", 0, 20),
+    Updater::Const("lfoSync", 0),
+    Updater::Const("lfoWave", 1), // 0-3 saw up, square, tri, s/h
+    Updater::Const("pitchModSens", 2), // 0-7
+    Updater::Const("ampModSens", 0), // 0-3
+    Updater::Const("transpose", 24), // 24 = C3
+    Updater::Const("polyMode", 0),
+    Updater::Const("pitchBendRange", 4), // 0-12
+    Updater::Const("portamentoMode", 0),
+    Updater::Const("portamentoTime", 0),
+    Updater::Const("footVolume", 40),
+    Updater::Const("sustain", 0),
+    Updater::Const("portamento", 0),
+    Updater::Const("chorus", 0),
+    Updater::Const("modWheelPitch", 50),
+    Updater::Const("modWheelAmp", 0),
+    Updater::Const("breathPitch", 0),
+    Updater::Const("breathAmp", 0),
+    Updater::Const("breathPitchBias", 50), // 50 = 0
+    Updater::Const("breathEgBias", 0)
+];
+
+pub const TX81Z_OPERATOR_LIMITS: ParamLimits = ParamLimits {
+    device: "TX81Z",
+    params: &[
+        ("env_attackRate", 0, 31), ("env_decay1Rate", 0, 31), ("env_decay2Rate", 0, 31), ("env_releaseRate", 1, 15), ("env_decay1Level", 0, 15),
+        ("levelScaling", 0, 99), ("rateScaling", 0, 3), ("egBiasSens", 0, 7), ("ampModEnable", 0, 1), ("keyVelocitySens", 0, 7),
+        ("outputLevel", 0, 99), ("freqCoarse", 0, 63), ("detune", 0, 6)
+    ]
+};
+
+pub const TX81Z_VOICE_LIMITS: ParamLimits = ParamLimits {
+    device: "TX81Z",
+    params: &[
+        ("algorithm", 0, 7), ("feedback", 0, 7),
+        ("lfoSpeed", 0, 99), ("lfoDelay", 0, 99), ("lfoPitchModDepth", 0, 99), ("lfoAmpModDepth", 0, 99), ("lfoSync", 0, 1), ("lfoWave", 0, 3),
+        ("pitchModSens", 0, 7), ("ampModSens", 0, 3), ("transpose", 0, 48), ("polyMode", 0, 1), ("pitchBendRange", 0, 12),
+        ("portamentoMode", 0, 1), ("portamentoTime", 0, 99), ("footVolume", 0, 99), ("sustain", 0, 1), ("portamento", 0, 1), ("chorus", 0, 1),
+        ("modWheelPitch", 0, 99), ("modWheelAmp", 0, 99), ("breathPitch", 0, 99), ("breathAmp", 0, 99), ("breathPitchBias", 0, 99), ("breathEgBias", 0, 99)
+    ]
+};
+
+// only used by the DX21, but part of every VCED
+pub const TX81Z_PITCH_EG_SPEC: [Updater; 6] = [
+    Updater::Const("env_pitch_rate1", 99),
+    Updater::Const("env_pitch_rate2", 99),
+    Updater::Const("env_pitch_rate3", 99),
+    Updater::Const("env_pitch_level1", 50),
+    Updater::Const("env_pitch_level2", 50),
+    Updater::Const("env_pitch_level3", 50)
+];
+
+// the additional parameters of the TX81Z, sent as an ACED before the VCED
+pub const TX81Z_ACED_OPERATOR_SPEC: [Updater; 5] = [
+    Updater::Const("fixedFreq", 0),
+    Updater::Const("fixedFreqRange", 0), // 0-7
+    Updater::Sweep("freqFine", 0, 15), // 0-15
+    Updater::Sweep("waveform", 0, 7), // 0-7, 0 = sine
+    Updater::Const("egShift", 0) // 0-3
+];
+
+pub const TX81Z_ACED_SPEC: [Updater; 3] = [
+    Updater::Const("reverbRate", 0), // 0-7
+    Updater::Const("footPitch", 0),
+    Updater::Const("footAmp", 0)
+];
+
+
+#[cfg(test)]
+mod test_voices {
+    use super::{
+        YamahaSysEx, FmModel,
+        TX81Z_PITCH_EG_SPEC, TX81Z_ACED_OPERATOR_SPEC, TX81Z_ACED_SPEC
+    };
+    use crate::modulation::{SysExComposer, Updater};
+
+    const DX7_VOICE_SIZE: usize = 155;
+    const TX81Z_VCED_SIZE: usize = 93;
+    const TX81Z_ACED_SIZE: usize = 23;
+
+    fn compose(sys_ex: &mut YamahaSysEx, updaters: &[Updater]) {
+        for u in updaters {
+            match u {
+                Updater::Const(_, c) => sys_ex.data(*c),
+                Updater::Sweep(_, min, _) => sys_ex.data(*min as i8),
+                _ => panic!("no other updaters in the FM specs")
+            }
+        }
+    }
+
+    fn voice(model: FmModel) -> YamahaSysEx {
+        let mut sys_ex = if model == FmModel::Dx7 { YamahaSysEx::dx7_voice() } else { YamahaSysEx::tx81z_vced() };
+        for _ in model.operators() {
+            compose(&mut sys_ex, model.operator_spec());
+        }
+        compose(&mut sys_ex, model.voice_spec());
+        sys_ex.name("fm");
+        sys_ex
+    }
+
+    fn sums_to_zero(msg: &[u8]) -> bool {
+        msg[6..msg.len() - 1].iter().map(|d| *d as u32).sum::<u32>() % 128 == 0
+    }
+
+    #[test]
+    fn composes_a_dx7_single_voice() {
+        let msg = voice(FmModel::Dx7).to_send();
+
+        assert_eq!(msg[..6], [0xF0, 0x43, 0x00, 0x00, 0x01, 0x1B]);
+        assert_eq!(msg.len(), 6 + DX7_VOICE_SIZE + 2);
+        assert_eq!(msg[6..8], [60, 20]);
+        assert_eq!(&msg[151..161], b"fm        ");
+        assert!(sums_to_zero(&msg));
+    }
+
+    #[test]
+    fn composes_a_tx81z_vced_and_aced() {
+        let mut vced = voice(FmModel::Tx81z);
+        compose(&mut vced, &TX81Z_PITCH_EG_SPEC);
+        let mut aced = YamahaSysEx::tx81z_aced();
+        for _ in FmModel::Tx81z.operators() {
+            compose(&mut aced, &TX81Z_ACED_OPERATOR_SPEC);
+        }
+        compose(&mut aced, &TX81Z_ACED_SPEC);
+
+        let (vced, aced) = (vced.to_send(), aced.to_send());
+
+        assert_eq!(vced[..6], [0xF0, 0x43, 0x00, 0x03, 0x00, 0x5D]);
+        assert_eq!(vced.len(), 6 + TX81Z_VCED_SIZE + 2);
+        assert_eq!(aced[..6], [0xF0, 0x43, 0x00, 0x7E, 0x00, 0x21]);
+        assert_eq!(&aced[6..16], b"LM  8976AE");
+        assert_eq!(aced.len(), 6 + 10 + TX81Z_ACED_SIZE + 2);
+        assert!(sums_to_zero(&vced));
+        assert!(sums_to_zero(&aced));
+    }
+
+    #[test]
+    fn finds_models_by_name() {
+        assert_eq!(FmModel::named("DX7"), Some(FmModel::Dx7));
+        assert_eq!(FmModel::named("tx81z"), Some(FmModel::Tx81z));
+        assert_eq!(FmModel::named("dx100"), None);
+    }
+}
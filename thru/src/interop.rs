@@ -21,7 +21,7 @@ pub fn post_cmd_to_recorder(data: JsonValue) {
     post_cmd(9009, data);
 }
 
-pub fn post_cmd_to_modulator(note: u8) {
-    post_cmd(7878, object!{ note: note });
+pub fn post_cmd_to_modulator(note: u8, velocity: u8) {
+    post_cmd(7878, object!{ note: note, velocity: velocity });
 }
 
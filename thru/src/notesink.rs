@@ -145,12 +145,13 @@ pub struct NotifyingRandomNoteDropper {
 impl MidiNoteSink for NotifyingRandomNoteDropper {
     fn receive(&self, n: &Note, stats: &mut NoteStats) {
         let note = n.note;
+        let velocity = n.velocity;
         let millis_since_last_dropped = stats.last_dropped.2.elapsed().as_millis();
         if RandomNoteDropper::should_play() && millis_since_last_dropped > 500u128 {
             self.next.receive(n, stats);
         } else {
             thread::spawn(move || {
-                post_cmd_to_modulator(note);
+                post_cmd_to_modulator(note, velocity);
             });
             stats.drop(note);
         }